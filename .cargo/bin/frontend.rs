@@ -4,10 +4,10 @@ use std::thread::JoinHandle;
 use std::{path::PathBuf, process::Command};
 
 #[cfg(windows)]
-pub const NPM: &'static str = "npm.cmd";
+pub const NPM: &str = "npm.cmd";
 
 #[cfg(not(windows))]
-pub const NPM: &'static str = "npm";
+pub const NPM: &str = "npm";
 
 pub const VITEJS_PORT: u16 = 5173;
 
@@ -1,7 +1,4 @@
-use std::{
-    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6, TcpListener, ToSocketAddrs},
-    ops::Range,
-};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6, TcpListener, ToSocketAddrs};
 
 /// binds a [`TcpListener`] to the given [`addr`](`ToSocketAddrs`)
 fn test_bind<A: ToSocketAddrs>(addr: A) -> bool {
@@ -18,9 +15,3 @@ pub fn is_port_free(port: u16) -> bool {
 
     test_bind(ipv6) && test_bind(ipv4)
 }
-
-/// max range is 65535
-#[must_use]
-pub fn find_free_port(mut range: Range<u16>) -> Option<u16> {
-    range.find(|port| is_port_free(*port))
-}
@@ -0,0 +1,58 @@
+use crate::sessions::batcher::MICRO_BATCHER;
+use crate::sessions::registry::MODEL_REGISTRY;
+use crate::utils::inference_pool::InferencePool;
+use rocket::http::Status;
+use rocket::serde::json::{json, Value};
+use rocket::{get, routes, State};
+
+use super::warmup::{is_ready, WARMUP_STATE};
+
+/// Liveness probe. Served on the async workers, so it keeps answering while
+/// the inference pool is saturated. Also reports the session pool metrics of
+/// every loaded matting model.
+#[get("/health")]
+pub fn health(pool: &State<InferencePool>) -> Value {
+    let sessions = MODEL_REGISTRY
+        .loaded()
+        .into_iter()
+        .filter_map(|entry| Some((entry.name.clone(), json!(entry.pool()?.metrics()))))
+        .collect::<rocket::serde::json::serde_json::Map<_, _>>();
+
+    json!({
+        "status": "ok",
+        "ready": is_ready(),
+        "inference": {
+            "running": pool.running(),
+            "waiting": pool.waiting(),
+            "max_concurrency": pool.config().max_concurrency,
+            "max_queue": pool.config().max_queue,
+        },
+        "batching": {
+            "max_batch_size": MICRO_BATCHER.config().max_batch_size,
+            "max_wait_ms": MICRO_BATCHER.config().max_wait_ms,
+        },
+        "sessions": sessions,
+    })
+}
+
+/// Readiness probe: 503 until the models have been loaded and warmed up at
+/// startup, or if any of them failed to, see
+/// [`warmup_fairing`](super::warmup::warmup_fairing). Reports how long each
+/// model took, and why it failed if it did.
+#[get("/ready")]
+pub fn ready() -> (Status, Value) {
+    let state = WARMUP_STATE
+        .read()
+        .unwrap_or_else(|error| error.into_inner());
+    let status = if state.ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+
+    (status, json!(*state))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![health, ready]
+}
@@ -0,0 +1,235 @@
+use crate::sessions::base::SessionError;
+use crate::sessions::registry::MODEL_REGISTRY;
+use crate::utils::inference_pool::InferencePoolError;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder, ImageReader};
+use std::collections::HashMap;
+use tempfile::NamedTempFile;
+
+use rocket::data::Data;
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::json;
+use rocket::tokio::io;
+use rocket_multipart_form_data::{
+    mime, MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
+};
+
+/// The uploaded images plus any text fields sent along with them.
+pub(crate) struct UploadedForm {
+    files: HashMap<String, NamedTempFile>,
+    texts: HashMap<String, String>,
+}
+
+impl UploadedForm {
+    pub(crate) fn file(&self, name: &str) -> io::Result<&NamedTempFile> {
+        self.files
+            .get(name)
+            .ok_or_else(|| io::Error::other(format!("No {} found", name)))
+    }
+
+    pub(crate) fn text(&self, name: &str) -> Option<&str> {
+        self.texts.get(name).map(|text| text.as_str())
+    }
+}
+
+/// Helper function to parse multipart form data and retrieve the uploaded image
+/// files together with the requested text fields.
+pub(crate) async fn parse_uploaded_form(
+    content_type: &ContentType,
+    data: Data<'_>,
+    file_fields: &[&str],
+    text_fields: &[&str],
+) -> io::Result<UploadedForm> {
+    let mut fields = file_fields
+        .iter()
+        .map(|name| {
+            MultipartFormDataField::file(name)
+                .size_limit(20 * 1024 * 1024) // 20MB
+                .content_type_by_string(Some(mime::IMAGE_STAR))
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    fields.extend(
+        text_fields
+            .iter()
+            .map(|name| MultipartFormDataField::text(name)),
+    );
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(fields);
+
+    let multipart_form_data = MultipartFormData::parse(content_type, data, options)
+        .await
+        .map_err(|_| io::Error::other("Failed to parse multipart form data"))?;
+
+    let mut files = HashMap::new();
+    for name in file_fields {
+        let file_field = multipart_form_data
+            .files
+            .get(*name)
+            .and_then(|files| files.first())
+            .ok_or_else(|| io::Error::other(format!("No {} found", name)))?;
+
+        // 创建一个临时文件
+        let temp_file = NamedTempFile::new().map_err(|e| {
+            log::error!("Error creating temp file: {:?}", e);
+            io::Error::other("Error creating temp file")
+        })?;
+
+        // 将上传的文件内容复制到临时文件
+        tokio::fs::copy(&file_field.path, temp_file.path())
+            .await
+            .map_err(|e| {
+                log::error!("Error copying file: {:?}", e);
+                io::Error::other("Error copying file")
+            })?;
+
+        files.insert(name.to_string(), temp_file);
+    }
+
+    let texts = multipart_form_data
+        .texts
+        .iter()
+        .filter_map(|(name, values)| {
+            values
+                .first()
+                .map(|value| (name.to_string(), value.text.clone()))
+        })
+        .collect();
+
+    Ok(UploadedForm { files, texts })
+}
+
+/// Helper function to decode an image from a file path.
+pub(crate) fn decode_image(file_path: &std::path::Path) -> io::Result<image::DynamicImage> {
+    let image_reader = ImageReader::open(file_path)?.with_guessed_format()?;
+    image_reader.decode().map_err(|error| {
+        log::error!("Error decoding image: {:?}", error);
+        io::Error::other("Error decoding image")
+    })
+}
+
+/// Helper function to read the size of an image from its header, without
+/// decoding it.
+pub(crate) fn image_dimensions(file_path: &std::path::Path) -> io::Result<(u32, u32)> {
+    let image_reader = ImageReader::open(file_path)?.with_guessed_format()?;
+    image_reader.into_dimensions().map_err(|error| {
+        log::error!("Error reading image size: {:?}", error);
+        io::Error::other("Error decoding image")
+    })
+}
+
+/// Helper function to encode an image buffer into the desired format.
+pub(crate) fn encode_image(
+    buffer: Vec<u8>,
+    width: u32,
+    height: u32,
+    color_type: ExtendedColorType,
+    format: &str,
+) -> io::Result<Vec<u8>> {
+    let mut output_buffer = Vec::new();
+    match format {
+        "png" => {
+            let encoder = PngEncoder::new_with_quality(
+                &mut output_buffer,
+                image::codecs::png::CompressionType::Best,
+                image::codecs::png::FilterType::Sub,
+            );
+            encoder
+                .write_image(&buffer, width, height, color_type)
+                .map_err(|error| {
+                    log::error!("Error encoding PNG: {:?}", error);
+                    io::Error::other(error.to_string())
+                })?;
+        }
+        "jpeg" => {
+            let encoder = JpegEncoder::new_with_quality(&mut output_buffer, 80);
+            encoder
+                .write_image(&buffer, width, height, color_type)
+                .map_err(|error| {
+                    log::error!("Error encoding JPEG: {:?}", error);
+                    io::Error::other(error.to_string())
+                })?;
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Unsupported image format",
+            ));
+        }
+    }
+    Ok(output_buffer)
+}
+
+/// Helper function to build the JSON error response for a session error: 400
+/// for an unknown model name, 404 for a disabled model, 503 when the model
+/// failed to load and 500 when it loaded but the request failed.
+pub(crate) fn session_error_response(
+    model: &str,
+    error: &SessionError,
+) -> (Status, (ContentType, Vec<u8>)) {
+    let (status, body) = match error {
+        SessionError::UnknownModel(_) => (
+            Status::BadRequest,
+            json!({
+                "model": model,
+                "error": error.to_string(),
+                "available_models": MODEL_REGISTRY.model_names(),
+            }),
+        ),
+        SessionError::ModelDisabled(_) => (
+            Status::NotFound,
+            json!({
+                "model": model,
+                "error": error.to_string(),
+            }),
+        ),
+        SessionError::ModelLoadError(_) => (
+            Status::ServiceUnavailable,
+            json!({
+                "model": model,
+                "error": error.to_string(),
+            }),
+        ),
+        _ => (
+            Status::InternalServerError,
+            json!({
+                "model": model,
+                "error": error.to_string(),
+            }),
+        ),
+    };
+    if status == Status::InternalServerError {
+        log::error!("Model {} failed: {}", model, error);
+    } else {
+        log::warn!("Model {} unavailable: {}", model, error);
+    }
+
+    (status, (ContentType::JSON, body.to_string().into_bytes()))
+}
+
+/// Helper function to build the JSON error response for an invalid request
+/// parameter.
+pub(crate) fn bad_request_response(message: &str) -> (Status, (ContentType, Vec<u8>)) {
+    error_response(Status::BadRequest, message)
+}
+
+/// Helper function to build a JSON error response with `status`.
+pub(crate) fn error_response(status: Status, message: &str) -> (Status, (ContentType, Vec<u8>)) {
+    let body = json!({ "error": message });
+
+    (status, (ContentType::JSON, body.to_string().into_bytes()))
+}
+
+/// Helper function to build the JSON error response for a job the inference
+/// pool refused or could not finish.
+pub(crate) fn pool_error_response(error: &InferencePoolError) -> (Status, (ContentType, Vec<u8>)) {
+    let status = match error {
+        InferencePoolError::QueueFull => Status::TooManyRequests,
+        InferencePoolError::QueueTimeout => Status::ServiceUnavailable,
+        InferencePoolError::JobFailed(_) => Status::InternalServerError,
+    };
+    log::warn!("Inference pool rejected request: {}", error);
+    let body = json!({ "error": error.to_string() });
+
+    (status, (ContentType::JSON, body.to_string().into_bytes()))
+}
@@ -0,0 +1,81 @@
+use super::helpers::{
+    decode_image, encode_image, parse_uploaded_form, pool_error_response, session_error_response,
+    UploadedForm,
+};
+use crate::sessions::base::SessionError;
+use crate::sessions::inpaint::{InpaintSession, INPAINT_MODEL};
+use crate::sessions::pool::{SessionGuard, SessionPool, SESSION_POOL_CONFIG};
+use crate::sessions::settings::SESSION_SETTINGS;
+use crate::utils::inference_pool::InferencePool;
+use image::ExtendedColorType;
+use once_cell::sync::OnceCell;
+use rocket::{post, routes, State};
+
+use rocket::data::Data;
+use rocket::http::{ContentType, Status};
+use rocket::tokio::io;
+
+pub(crate) static INPAINT_SESSION: OnceCell<Result<SessionPool<InpaintSession>, SessionError>> =
+    OnceCell::new();
+
+/// Helper function to initialize the MI-GAN session pool and check out a
+/// session. A failed load is remembered and reported on every following request.
+pub(crate) fn get_inpaint_session() -> Result<SessionGuard<'static, InpaintSession>, SessionError> {
+    let session_options = SESSION_SETTINGS.get().session_options(INPAINT_MODEL)?;
+
+    INPAINT_SESSION
+        .get_or_init(|| {
+            SessionPool::new(SESSION_POOL_CONFIG.get(), session_options, |options| {
+                InpaintSession::new(true, options)
+            })
+        })
+        .as_ref()
+        .map(SessionPool::checkout)
+        .map_err(Clone::clone)
+}
+
+/// Fills the masked area and encodes the result as PNG. Runs on the inference pool.
+fn fill_masked_area(form: UploadedForm) -> io::Result<(Status, (ContentType, Vec<u8>))> {
+    let original_img = decode_image(form.file("file")?.path())?;
+    let mask_img = decode_image(form.file("mask")?.path())?;
+    let session = match get_inpaint_session() {
+        Ok(session) => session,
+        Err(error) => return Ok(session_error_response(INPAINT_MODEL, &error)),
+    };
+
+    let output_img_tensor = session.run(original_img, mask_img).map_err(|error| {
+        log::error!("Error running session: {:?}", error);
+        io::Error::other(error.to_string())
+    })?;
+
+    let (height, width, _) = output_img_tensor.dim();
+    let img_buffer = output_img_tensor.into_raw_vec();
+
+    let output_buffer = encode_image(
+        img_buffer,
+        width as u32,
+        height as u32,
+        ExtendedColorType::Rgb8,
+        "png",
+    )?;
+    Ok((Status::Ok, (ContentType::PNG, output_buffer)))
+}
+
+/// Fills the white area of `mask` in `file` and returns the result as PNG.
+#[post("/inpaint", format = "multipart/form-data", data = "<data>")]
+pub async fn inpaint(
+    content_type: &ContentType,
+    data: Data<'_>,
+    pool: &State<InferencePool>,
+) -> io::Result<(Status, (ContentType, Vec<u8>))> {
+    let form = parse_uploaded_form(content_type, data, &["file", "mask"], &[]).await?;
+
+    match pool.run(move || fill_masked_area(form)).await {
+        Ok(response) => response,
+        Err(error) => Ok(pool_error_response(&error)),
+    }
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![inpaint]
+}
@@ -1 +1,7 @@
+pub mod health;
+mod helpers;
+pub mod inpaint;
+pub mod models;
 pub mod rembg;
+pub mod upscale;
+pub mod warmup;
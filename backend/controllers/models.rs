@@ -0,0 +1,101 @@
+use crate::sessions::base::SessionError;
+use crate::sessions::inpaint::INPAINT_MODEL;
+use crate::sessions::pool::{SessionPool, SessionPoolConfig};
+use crate::sessions::realesrgan::{upscale_model_name, SUPPORTED_SCALES};
+use crate::sessions::registry::MODEL_REGISTRY;
+use crate::sessions::settings::{SessionSettings, SESSION_SETTINGS};
+use crate::utils::config::ConfigSection;
+use once_cell::sync::OnceCell;
+use rocket::fairing::AdHoc;
+use rocket::serde::json::{json, Value};
+use rocket::{get, routes, State};
+
+use super::inpaint::INPAINT_SESSION;
+use super::upscale::upscale_session_cell;
+
+/// Every model the server knows about: the matting registry, MI-GAN and the
+/// Real-ESRGAN scales.
+pub(crate) fn known_models() -> Vec<String> {
+    let mut models = MODEL_REGISTRY.all_model_names();
+    models.push(INPAINT_MODEL.to_owned());
+    models.extend(SUPPORTED_SCALES.map(upscale_model_name));
+    models
+}
+
+/// Load state of a session pool kept in a `OnceCell`, for the models outside
+/// the registry.
+fn pool_state<T>(cell: &OnceCell<Result<SessionPool<T>, SessionError>>) -> Value {
+    match cell.get() {
+        Some(Ok(pool)) => json!({ "loaded": true, "pool": pool.metrics() }),
+        Some(Err(error)) => json!({ "loaded": false, "error": error.to_string() }),
+        None => json!({ "loaded": false }),
+    }
+}
+
+fn model_state(model: &str) -> Value {
+    if model == INPAINT_MODEL {
+        return pool_state(&INPAINT_SESSION);
+    }
+    if let Some(cell) = SUPPORTED_SCALES
+        .into_iter()
+        .find(|&scale| upscale_model_name(scale) == model)
+        .and_then(upscale_session_cell)
+    {
+        return pool_state(cell);
+    }
+
+    match MODEL_REGISTRY.entry(model) {
+        Some(entry) => match (entry.pool(), entry.load_error()) {
+            (Some(pool), _) => json!({ "loaded": true, "pool": pool.metrics() }),
+            (None, Some(error)) => json!({ "loaded": false, "error": error.to_string() }),
+            (None, None) => json!({ "loaded": false }),
+        },
+        None => json!({ "loaded": false }),
+    }
+}
+
+/// Effective session settings and load state of every model.
+#[get("/models")]
+pub fn models(settings: &State<SessionSettings>, pool_config: &State<SessionPoolConfig>) -> Value {
+    let models = known_models()
+        .into_iter()
+        .map(|model| {
+            let mut state = model_state(&model);
+            state["settings"] = json!(settings.model(&model));
+            (model, state)
+        })
+        .collect::<rocket::serde::json::serde_json::Map<_, _>>();
+
+    json!({
+        "session_pool": pool_config.inner(),
+        "models": models,
+    })
+}
+
+/// Validates `[default.sessions]` on ignition, aborting launch when it is
+/// invalid, and logs the settings every model will be loaded with. The
+/// validated settings are what sessions load with and what `/models` shows.
+pub fn settings_fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Session settings", |rocket| async {
+        let settings = SessionSettings::from_figment(rocket.figment())
+            .and_then(|settings| settings.validate(&known_models()).map(|_| settings))
+            .and_then(|settings| SESSION_SETTINGS.set(settings.clone()).map(|_| settings));
+
+        match settings {
+            Ok(settings) => {
+                for model in known_models() {
+                    log::info!("Model {}: {:?}", model, settings.model(&model));
+                }
+                Ok(rocket.manage(settings))
+            }
+            Err(error) => {
+                log::error!("{}", error);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![models]
+}
@@ -1,145 +1,415 @@
-use crate::sessions::base::{BaseSessionTrait, SessionOptions};
-use crate::sessions::birefnet::BirefnetSession;
-use image::codecs::jpeg::JpegEncoder;
-use image::codecs::png::PngEncoder;
-use image::{ExtendedColorType, ImageEncoder, ImageReader};
-use once_cell::sync::OnceCell;
-use rocket::{post, routes};
-use std::path::PathBuf;
-use tempfile::NamedTempFile;
+use super::helpers::{
+    bad_request_response, decode_image, encode_image, parse_uploaded_form, pool_error_response,
+    session_error_response, UploadedForm,
+};
+use crate::sessions::alpha_matting::{self, AlphaMattingOptions};
+use crate::sessions::base::{MattingOptions, SessionError};
+use crate::sessions::batcher::{BatchError, MICRO_BATCHER};
+use crate::sessions::components::ComponentFilter;
+use crate::sessions::ensemble::Ensemble;
+use crate::sessions::foreground;
+use crate::sessions::mask_filter::MaskFilter;
+use crate::sessions::registry::{DEFAULT_MODEL, MODEL_REGISTRY};
+use crate::sessions::settings::SESSION_SETTINGS;
+use crate::utils::inference_pool::InferencePool;
+use image::{DynamicImage, ExtendedColorType};
+use ndarray::Array3;
+use rocket::{post, routes, FromForm, State};
 
 use rocket::data::Data;
 use rocket::http::{ContentType, Status};
 use rocket::tokio::io;
-use rocket_multipart_form_data::{
-    mime, MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
-};
 
-static BIREFNET_SESSION: OnceCell<BirefnetSession> = OnceCell::new();
+/// Picks the model name from the form field, then the query string, then the default.
+fn select_model<'a>(form: &'a UploadedForm, query_model: Option<&'a str>) -> &'a str {
+    form.text("model")
+        .or(query_model)
+        .filter(|model| !model.trim().is_empty())
+        .unwrap_or(DEFAULT_MODEL)
+}
 
-/// Helper function to parse multipart form data and retrieve the uploaded file.
-async fn parse_uploaded_file(
-    content_type: &ContentType,
-    data: Data<'_>,
-) -> io::Result<NamedTempFile> {
-    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
-        MultipartFormDataField::file("file")
-            .size_limit(20 * 1024 * 1024) // 20MB
-            .content_type_by_string(Some(mime::IMAGE_STAR))
-            .unwrap(),
-    ]);
-
-    let multipart_form_data = MultipartFormData::parse(content_type, data, options)
-        .await
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to parse multipart form data"))?;
-
-    let file_field = multipart_form_data
-        .files
-        .get("file")
-        .and_then(|files| files.first())
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No file found"))?;
-
-    // 创建一个临时文件
-    let temp_file = NamedTempFile::new().map_err(|e| {
-        log::error!("Error creating temp file: {:?}", e);
-        io::Error::new(io::ErrorKind::Other, "Error creating temp file")
-    })?;
-
-    // 将上传的文件内容复制到临时文件
-    tokio::fs::copy(&file_field.path, temp_file.path())
-        .await
-        .map_err(|e| {
-            log::error!("Error copying file: {:?}", e);
-            io::Error::new(io::ErrorKind::Other, "Error copying file")
-        })?;
-
-    Ok(temp_file)
-}
-
-/// Helper function to decode an image from a file path.
-fn decode_image(file_path: &std::path::Path) -> io::Result<image::DynamicImage> {
-    let image_reader = ImageReader::open(file_path)?.with_guessed_format()?;
-    image_reader.decode().map_err(|error| {
-        log::error!("Error decoding image: {:?}", error);
-        io::Error::new(io::ErrorKind::Other, "Error decoding image")
+/// Reads an option from the form field, then the query string. Values are
+/// trimmed and empty ones are ignored.
+fn field<'a>(form: &'a UploadedForm, name: &str, query: Option<&'a str>) -> Option<String> {
+    form.text(name)
+        .or(query)
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_owned)
+}
+
+/// Form fields and query parameters of the matting routes, besides `file`.
+const TEXT_FIELDS: [&str; 23] = [
+    "model",
+    "resize_mode",
+    "tiled",
+    "scales",
+    "tta",
+    "tta_fusion",
+    "ensemble_fusion",
+    "upsampler",
+    "alpha_matting",
+    "alpha_matting_foreground_threshold",
+    "alpha_matting_background_threshold",
+    "alpha_matting_erode_size",
+    "foreground_estimation",
+    "threshold",
+    "morphology",
+    "morphology_size",
+    "keep_largest",
+    "min_area",
+    "min_relative_area",
+    "fill_holes",
+    "feather",
+    "gamma",
+    "contrast",
+];
+
+/// Query parameters of the matting routes. Every one can also be sent as a
+/// form field, which takes precedence.
+#[derive(Debug, FromForm)]
+pub struct MattingQuery<'r> {
+    model: Option<&'r str>,
+    resize_mode: Option<&'r str>,
+    tiled: Option<&'r str>,
+    scales: Option<&'r str>,
+    tta: Option<&'r str>,
+    tta_fusion: Option<&'r str>,
+    ensemble_fusion: Option<&'r str>,
+    upsampler: Option<&'r str>,
+    alpha_matting: Option<&'r str>,
+    alpha_matting_foreground_threshold: Option<&'r str>,
+    alpha_matting_background_threshold: Option<&'r str>,
+    alpha_matting_erode_size: Option<&'r str>,
+    foreground_estimation: Option<&'r str>,
+    threshold: Option<&'r str>,
+    morphology: Option<&'r str>,
+    morphology_size: Option<&'r str>,
+    keep_largest: Option<&'r str>,
+    min_area: Option<&'r str>,
+    min_relative_area: Option<&'r str>,
+    fill_holes: Option<&'r str>,
+    feather: Option<&'r str>,
+    gamma: Option<&'r str>,
+    contrast: Option<&'r str>,
+}
+
+/// What a matting request asks for: a single model or an ensemble of models,
+/// the options to run them with, whether to refine the mask with alpha
+/// matting, how to post-process it and whether to estimate the foreground
+/// colors of the cutout.
+struct MattingRequest {
+    model: String,
+    ensemble: Option<Ensemble>,
+    options: MattingOptions,
+    alpha_matting: Option<AlphaMattingOptions>,
+    mask_filter: MaskFilter,
+    foreground_estimation: bool,
+}
+
+impl MattingRequest {
+    /// The model whose session post-processes the mask, the first member of
+    /// an ensemble.
+    fn post_process_model(&self) -> &str {
+        self.ensemble
+            .as_ref()
+            .and_then(|ensemble| ensemble.models.first())
+            .unwrap_or(&self.model)
+    }
+}
+
+/// Reads the model and options of a matting request from the form fields and
+/// the query string.
+fn select_request(form: &UploadedForm, query: &MattingQuery<'_>) -> Result<MattingRequest, String> {
+    let model = select_model(form, query.model).trim().to_owned();
+    let fusion = field(form, "ensemble_fusion", query.ensemble_fusion)
+        .map(|fusion| fusion.parse())
+        .transpose()?
+        .unwrap_or_default();
+    let ensemble = Ensemble::parse(&model, fusion).transpose()?;
+
+    Ok(MattingRequest {
+        model,
+        ensemble,
+        options: select_options(form, query)?,
+        alpha_matting: select_alpha_matting(form, query)?,
+        mask_filter: select_mask_filter(form, query)?,
+        foreground_estimation: field(form, "foreground_estimation", query.foreground_estimation)
+            .map(|value| parse_flag("foreground_estimation", &value))
+            .transpose()?
+            .unwrap_or(false),
     })
 }
 
-/// Helper function to initialize or retrieve the Birefnet session.
-fn get_birefnet_session() -> io::Result<&'static BirefnetSession> {
-    let session_options = SessionOptions::new()
-        .with_providers(vec!["cpu".to_owned()])
-        .build()
-        .map_err(|error| {
-            log::error!("Error creating session options: {:?}", error);
-            io::Error::new(io::ErrorKind::Other, error.to_string())
-        })?;
-
-    Ok(BIREFNET_SESSION.get_or_init(|| BirefnetSession::new(true, session_options)))
-}
-
-/// Helper function to encode an image buffer into the desired format.
-fn encode_image(
-    buffer: Vec<u8>,
-    width: u32,
-    height: u32,
-    color_type: ExtendedColorType,
-    format: &str,
-) -> io::Result<Vec<u8>> {
-    let mut output_buffer = Vec::new();
-    match format {
-        "png" => {
-            let encoder = PngEncoder::new_with_quality(
-                &mut output_buffer,
-                image::codecs::png::CompressionType::Best,
-                image::codecs::png::FilterType::Sub,
-            );
-            encoder
-                .write_image(&buffer, width, height, color_type)
-                .map_err(|error| {
-                    log::error!("Error encoding PNG: {:?}", error);
-                    io::Error::new(io::ErrorKind::Other, error.to_string())
-                })?;
-        }
-        "jpeg" => {
-            let encoder = JpegEncoder::new_with_quality(&mut output_buffer, 80);
-            encoder
-                .write_image(&buffer, width, height, color_type)
-                .map_err(|error| {
-                    log::error!("Error encoding JPEG: {:?}", error);
-                    io::Error::new(io::ErrorKind::Other, error.to_string())
-                })?;
-        }
-        _ => {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Unsupported image format",
-            ));
+/// Largest `morphology_size` and `feather`, beyond which the filters are
+/// mostly time spent blurring or wiping out the whole mask.
+const MAX_MORPHOLOGY_SIZE: u32 = 101;
+const MAX_FEATHER: f32 = 100.0;
+
+/// Reads the mask post-processing steps. Each is skipped unless given.
+fn select_mask_filter(form: &UploadedForm, query: &MattingQuery<'_>) -> Result<MaskFilter, String> {
+    let morphology = field(form, "morphology", query.morphology)
+        .map(|morphology| morphology.parse())
+        .transpose()?;
+    let morphology_size = field(form, "morphology_size", query.morphology_size)
+        .map(|size| match size.parse::<u32>() {
+            Ok(size) if (1..=MAX_MORPHOLOGY_SIZE).contains(&size) => Ok(size),
+            _ => Err(format!(
+                "Invalid morphology_size {}, expected a size between 1 and {}",
+                size, MAX_MORPHOLOGY_SIZE
+            )),
+        })
+        .transpose()?
+        .unwrap_or(3);
+
+    Ok(MaskFilter {
+        threshold: field(form, "threshold", query.threshold)
+            .map(|threshold| threshold.parse())
+            .transpose()?,
+        morphology: morphology.map(|morphology| (morphology, morphology_size)),
+        components: ComponentFilter {
+            keep_largest: field(form, "keep_largest", query.keep_largest)
+                .map(|keep_largest| parse_flag("keep_largest", &keep_largest))
+                .transpose()?
+                .unwrap_or(false),
+            min_area: field(form, "min_area", query.min_area)
+                .map(|area| parse_area("min_area", &area))
+                .transpose()?,
+            min_relative_area: field(form, "min_relative_area", query.min_relative_area)
+                .map(|area| parse_factor("min_relative_area", &area, 1.0))
+                .transpose()?,
+            fill_holes: field(form, "fill_holes", query.fill_holes)
+                .map(|area| parse_area("fill_holes", &area))
+                .transpose()?,
+        },
+        feather: field(form, "feather", query.feather)
+            .map(|feather| parse_factor("feather", &feather, MAX_FEATHER))
+            .transpose()?,
+        gamma: field(form, "gamma", query.gamma)
+            .map(|gamma| parse_factor("gamma", &gamma, 10.0))
+            .transpose()?,
+        contrast: field(form, "contrast", query.contrast)
+            .map(|contrast| parse_factor("contrast", &contrast, 10.0))
+            .transpose()?,
+    })
+}
+
+/// Parses an area in pixels.
+fn parse_area(name: &str, value: &str) -> Result<u32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {} {}, expected an area in pixels", name, value))
+}
+
+/// Parses a number above 0 and at most `max`.
+fn parse_factor(name: &str, value: &str, max: f32) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(factor) if factor > 0.0 && factor <= max => Ok(factor),
+        _ => Err(format!(
+            "Invalid {} {}, expected a number above 0 and at most {}",
+            name, value, max
+        )),
+    }
+}
+
+/// Largest `alpha_matting_erode_size`, wider bands are mostly solver time.
+const MAX_ERODE_SIZE: u32 = 100;
+
+/// Reads the alpha matting options, `None` unless `alpha_matting` is set.
+/// Thresholds and erode size default to rembg's.
+fn select_alpha_matting(
+    form: &UploadedForm,
+    query: &MattingQuery<'_>,
+) -> Result<Option<AlphaMattingOptions>, String> {
+    let enabled = field(form, "alpha_matting", query.alpha_matting)
+        .map(|enabled| parse_flag("alpha_matting", &enabled))
+        .transpose()?
+        .unwrap_or(false);
+    if !enabled {
+        return Ok(None);
+    }
+
+    let threshold = |name: &str, query_value: Option<&str>, default: u8| {
+        field(form, name, query_value)
+            .map(|value| {
+                value.parse::<u8>().map_err(|_| {
+                    format!(
+                        "Invalid {} {}, expected a value between 0 and 255",
+                        name, value
+                    )
+                })
+            })
+            .transpose()
+            .map(|value| value.unwrap_or(default))
+    };
+    let defaults = AlphaMattingOptions::default();
+    let options = AlphaMattingOptions {
+        foreground_threshold: threshold(
+            "alpha_matting_foreground_threshold",
+            query.alpha_matting_foreground_threshold,
+            defaults.foreground_threshold,
+        )?,
+        background_threshold: threshold(
+            "alpha_matting_background_threshold",
+            query.alpha_matting_background_threshold,
+            defaults.background_threshold,
+        )?,
+        erode_size: field(
+            form,
+            "alpha_matting_erode_size",
+            query.alpha_matting_erode_size,
+        )
+        .map(|size| match size.parse::<u32>() {
+            Ok(size) if size <= MAX_ERODE_SIZE => Ok(size),
+            _ => Err(format!(
+                "Invalid alpha_matting_erode_size {}, expected a size between 0 and {}",
+                size, MAX_ERODE_SIZE
+            )),
+        })
+        .transpose()?
+        .unwrap_or(defaults.erode_size),
+    };
+
+    if options.background_threshold >= options.foreground_threshold {
+        return Err(format!(
+            "Invalid alpha matting thresholds, the background threshold {} must be below the foreground threshold {}",
+            options.background_threshold, options.foreground_threshold
+        ));
+    }
+    Ok(Some(options))
+}
+
+/// Reads the per-request matting options from the form fields and the query
+/// string. Empty values are ignored.
+fn select_options(form: &UploadedForm, query: &MattingQuery<'_>) -> Result<MattingOptions, String> {
+    Ok(MattingOptions {
+        resize_mode: field(form, "resize_mode", query.resize_mode)
+            .map(|mode| mode.parse())
+            .transpose()?,
+        tiled: field(form, "tiled", query.tiled)
+            .map(|tiled| parse_flag("tiled", &tiled))
+            .transpose()?
+            .unwrap_or(false),
+        scales: field(form, "scales", query.scales)
+            .map(|scales| parse_scales(&scales))
+            .transpose()?
+            .unwrap_or_default(),
+        scale: None,
+        tta: field(form, "tta", query.tta)
+            .map(|tta| tta.parse())
+            .transpose()?,
+        tta_fusion: field(form, "tta_fusion", query.tta_fusion)
+            .map(|fusion| fusion.parse())
+            .transpose()?
+            .unwrap_or_default(),
+        upsampler: field(form, "upsampler", query.upsampler)
+            .map(|upsampler| upsampler.parse())
+            .transpose()?,
+    })
+}
+
+/// Most scales a request can ask for, as every scale is a full model run.
+const MAX_SCALES: usize = 4;
+
+/// Parses a comma-separated list of input sizes, such as `768,1024,1280`.
+fn parse_scales(value: &str) -> Result<Vec<u32>, String> {
+    let scales = value
+        .split(',')
+        .map(|scale| match scale.trim().parse::<u32>() {
+            Ok(scale) if (32..=4096).contains(&scale) => Ok(scale),
+            _ => Err(format!(
+                "Invalid scale {}, expected a size between 32 and 4096",
+                scale.trim()
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if scales.len() > MAX_SCALES {
+        return Err(format!("Too many scales, at most {} allowed", MAX_SCALES));
+    }
+    Ok(scales)
+}
+
+fn parse_flag(name: &str, value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(format!(
+            "Invalid {} {}, expected true or false",
+            name, value
+        )),
+    }
+}
+
+/// Helper function to predict the alpha mask of `image` with the requested
+/// model or ensemble, loading the models on first use. Requests that arrive
+/// close together are batched. The mask is then refined with alpha matting and
+/// post-processed if the request asks for it.
+///
+/// Session errors come with the model to report them for: the requested one,
+/// or the member of an ensemble that failed.
+fn predict_mask(
+    request: &MattingRequest,
+    image: &DynamicImage,
+) -> Result<io::Result<Array3<u8>>, (String, SessionError)> {
+    let result = match &request.ensemble {
+        Some(ensemble) => ensemble.run(image, &request.options),
+        None => SESSION_SETTINGS
+            .get()
+            .session_options(&request.model)
+            .map_err(BatchError::from)
+            .and_then(|session_options| {
+                MICRO_BATCHER.run(
+                    &request.model,
+                    session_options,
+                    image.clone(),
+                    request.options.clone(),
+                )
+            })
+            .map_err(|error| (request.model.clone(), error)),
+    };
+
+    match result {
+        Ok(mask) => {
+            let mask = match &request.alpha_matting {
+                Some(options) => alpha_matting::refine(image, mask, options)
+                    .map_err(|error| (request.model.clone(), error))?,
+                None => mask,
+            };
+            Ok(Ok(request.mask_filter.apply(mask)))
         }
+        Err((model, BatchError::Session(error))) => Err((model, error)),
+        Err((_, BatchError::Run(error))) => Ok(Err(io::Error::other(error))),
     }
-    Ok(output_buffer)
 }
 
-#[post("/rembg/image", format = "multipart/form-data", data = "<data>")]
-pub async fn rembg(
-    content_type: &ContentType,
-    data: Data<'_>,
+/// Removes the background of the uploaded image and encodes the result as PNG,
+/// with the colors of its semi-transparent edges decontaminated if the request
+/// asks for foreground estimation. Runs on the inference pool.
+fn remove_background(
+    form: UploadedForm,
+    request: &MattingRequest,
 ) -> io::Result<(Status, (ContentType, Vec<u8>))> {
-    let temp_file = parse_uploaded_file(content_type, data).await?;
-    let original_img = decode_image(temp_file.path())?;
-    let session = get_birefnet_session()?;
-
-    let alpha_mask = session.run(original_img.clone()).map_err(|error| {
-        log::error!("Error running session: {:?}", error);
-        io::Error::new(io::ErrorKind::Other, error.to_string())
-    })?;
-
-    let output_img_tensor = session
-        .post_process(alpha_mask, original_img)
-        .map_err(|error| {
-            log::error!("Error post-processing image: {:?}", error);
-            io::Error::new(io::ErrorKind::Other, error.to_string())
-        })?;
+    let original_img = decode_image(form.file("file")?.path())?;
+    let alpha_mask = match predict_mask(request, &original_img) {
+        Ok(alpha_mask) => alpha_mask?,
+        Err((model, error)) => return Ok(session_error_response(&model, &error)),
+    };
+
+    let session = MODEL_REGISTRY
+        .loaded_session(request.post_process_model())
+        .ok_or_else(|| io::Error::other(SessionError::PredictError))?;
+    let mut output_img_tensor =
+        session
+            .post_process(alpha_mask, original_img)
+            .map_err(|error| {
+                log::error!("Error post-processing image: {:?}", error);
+                io::Error::other(error.to_string())
+            })?;
+    if request.foreground_estimation {
+        output_img_tensor = foreground::decontaminate(output_img_tensor)
+            .map_err(|error| io::Error::other(error.to_string()))?;
+    }
 
     let (height, width, _) = output_img_tensor.dim();
     let img_buffer = output_img_tensor.into_raw_vec();
@@ -154,19 +424,17 @@ pub async fn rembg(
     Ok((Status::Ok, (ContentType::PNG, output_buffer)))
 }
 
-#[post("/rembg/mask", format = "multipart/form-data", data = "<data>")]
-pub async fn mask(
-    content_type: &ContentType,
-    data: Data<'_>,
+/// Predicts the alpha mask of the uploaded image and encodes it as JPEG.
+/// Runs on the inference pool.
+fn generate_mask(
+    form: UploadedForm,
+    request: &MattingRequest,
 ) -> io::Result<(Status, (ContentType, Vec<u8>))> {
-    let temp_file = parse_uploaded_file(content_type, data).await?;
-    let original_img = decode_image(temp_file.path())?;
-    let session = get_birefnet_session()?;
-
-    let alpha_mask = session.run(original_img).map_err(|error| {
-        log::error!("Error running session: {:?}", error);
-        io::Error::new(io::ErrorKind::Other, error.to_string())
-    })?;
+    let original_img = decode_image(form.file("file")?.path())?;
+    let alpha_mask = match predict_mask(request, &original_img) {
+        Ok(alpha_mask) => alpha_mask?,
+        Err((model, error)) => return Ok(session_error_response(&model, &error)),
+    };
 
     let (height, width, _) = alpha_mask.dim();
     let img_buffer = alpha_mask.into_raw_vec();
@@ -181,6 +449,58 @@ pub async fn mask(
     Ok((Status::Ok, (ContentType::JPEG, output_buffer)))
 }
 
+#[post(
+    "/rembg/image?<query..>",
+    format = "multipart/form-data",
+    data = "<data>"
+)]
+pub async fn rembg(
+    content_type: &ContentType,
+    data: Data<'_>,
+    query: MattingQuery<'_>,
+    pool: &State<InferencePool>,
+) -> io::Result<(Status, (ContentType, Vec<u8>))> {
+    let form = parse_uploaded_form(content_type, data, &["file"], &TEXT_FIELDS).await?;
+    let request = match select_request(&form, &query) {
+        Ok(request) => request,
+        Err(message) => return Ok(bad_request_response(&message)),
+    };
+
+    match pool.run(move || remove_background(form, &request)).await {
+        Ok(response) => response,
+        Err(error) => Ok(pool_error_response(&error)),
+    }
+}
+
+#[post(
+    "/rembg/mask?<query..>",
+    format = "multipart/form-data",
+    data = "<data>"
+)]
+pub async fn mask(
+    content_type: &ContentType,
+    data: Data<'_>,
+    query: MattingQuery<'_>,
+    pool: &State<InferencePool>,
+) -> io::Result<(Status, (ContentType, Vec<u8>))> {
+    let form = parse_uploaded_form(content_type, data, &["file"], &TEXT_FIELDS).await?;
+    let request = match select_request(&form, &query) {
+        Ok(request) => request,
+        Err(message) => return Ok(bad_request_response(&message)),
+    };
+    // The mask has no colors to decontaminate.
+    if request.foreground_estimation {
+        return Ok(bad_request_response(
+            "foreground_estimation only applies to /rembg/image",
+        ));
+    }
+
+    match pool.run(move || generate_mask(form, &request)).await {
+        Ok(response) => response,
+        Err(error) => Ok(pool_error_response(&error)),
+    }
+}
+
 pub fn routes() -> Vec<rocket::Route> {
     routes![rembg, mask]
 }
@@ -0,0 +1,167 @@
+use super::helpers::{
+    bad_request_response, decode_image, encode_image, error_response, image_dimensions,
+    parse_uploaded_form, pool_error_response, session_error_response, UploadedForm,
+};
+use crate::sessions::base::SessionError;
+use crate::sessions::pool::{SessionGuard, SessionPool, SESSION_POOL_CONFIG};
+use crate::sessions::realesrgan::{upscale_model_name, RealEsrganSession, SUPPORTED_SCALES};
+use crate::sessions::settings::SESSION_SETTINGS;
+use crate::utils::config::{ConfigCell, ConfigSection};
+use crate::utils::inference_pool::InferencePool;
+use image::ExtendedColorType;
+use once_cell::sync::OnceCell;
+use rocket::serde::Deserialize;
+use rocket::{post, routes, State};
+
+use rocket::data::Data;
+use rocket::http::{ContentType, Status};
+use rocket::tokio::io;
+
+const DEFAULT_SCALE: u32 = 4;
+
+/// `[default.upscale]` section of Rocket.toml.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+pub struct UpscaleConfig {
+    /// Largest output, in pixels, a request may ask for. The output is held
+    /// in memory as 8-bit RGB and then encoded, so this bounds the memory of
+    /// an upscale to a few times `3 * max_output_pixels` bytes.
+    pub max_output_pixels: u64,
+}
+
+impl Default for UpscaleConfig {
+    fn default() -> Self {
+        Self {
+            max_output_pixels: 64_000_000,
+        }
+    }
+}
+
+impl ConfigSection for UpscaleConfig {
+    const SECTION: &'static str = "upscale";
+
+    fn validate(&self) -> Result<(), String> {
+        if self.max_output_pixels == 0 {
+            return Err("max_output_pixels must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// `[default.upscale]`, validated at ignite.
+pub static UPSCALE_CONFIG: ConfigCell<UpscaleConfig> = ConfigCell::new();
+
+pub(crate) type UpscaleSessionCell = OnceCell<Result<SessionPool<RealEsrganSession>, SessionError>>;
+
+static REALESRGAN_X2_SESSION: UpscaleSessionCell = OnceCell::new();
+static REALESRGAN_X4_SESSION: UpscaleSessionCell = OnceCell::new();
+static REALESRGAN_X8_SESSION: UpscaleSessionCell = OnceCell::new();
+
+/// The session pool cell of the Real-ESRGAN model for `scale`.
+pub(crate) fn upscale_session_cell(scale: u32) -> Option<&'static UpscaleSessionCell> {
+    match scale {
+        2 => Some(&REALESRGAN_X2_SESSION),
+        4 => Some(&REALESRGAN_X4_SESSION),
+        8 => Some(&REALESRGAN_X8_SESSION),
+        _ => None,
+    }
+}
+
+/// Helper function to initialize the Real-ESRGAN session pool for `scale` and
+/// check out a session. A failed load is remembered and reported on every
+/// following request.
+pub(crate) fn get_upscale_session(
+    scale: u32,
+) -> Result<SessionGuard<'static, RealEsrganSession>, SessionError> {
+    let model_name = upscale_model_name(scale);
+    let cell = upscale_session_cell(scale)
+        .ok_or_else(|| SessionError::UnknownModel(model_name.clone()))?;
+
+    let session_options = SESSION_SETTINGS.get().session_options(&model_name)?;
+
+    cell.get_or_init(|| {
+        SessionPool::new(SESSION_POOL_CONFIG.get(), session_options, |options| {
+            RealEsrganSession::new(true, options, scale)
+        })
+    })
+    .as_ref()
+    .map(SessionPool::checkout)
+    .map_err(Clone::clone)
+}
+
+/// Upscales the uploaded image and encodes the result as PNG, unless the
+/// output would exceed `max_output_pixels`. Runs on the inference pool.
+fn upscale_image(form: UploadedForm, scale: u32) -> io::Result<(Status, (ContentType, Vec<u8>))> {
+    let path = form.file("file")?.path();
+    let (width, height) = image_dimensions(path)?;
+    let output_pixels = width as u64 * height as u64 * (scale * scale) as u64;
+    let max_output_pixels = UPSCALE_CONFIG.get().max_output_pixels;
+    if output_pixels > max_output_pixels {
+        let message = format!(
+            "Output of {}x{} at x{} would be {} pixels, at most {} allowed",
+            width, height, scale, output_pixels, max_output_pixels
+        );
+        log::warn!("{}", message);
+        return Ok(error_response(Status::PayloadTooLarge, &message));
+    }
+
+    let original_img = decode_image(path)?;
+    let session = match get_upscale_session(scale) {
+        Ok(session) => session,
+        Err(error) => return Ok(session_error_response(&upscale_model_name(scale), &error)),
+    };
+
+    let output_img_tensor = session.run(original_img).map_err(|error| {
+        log::error!("Error running session: {:?}", error);
+        io::Error::other(error.to_string())
+    })?;
+
+    let (height, width, _) = output_img_tensor.dim();
+    let img_buffer = output_img_tensor.into_raw_vec();
+
+    let output_buffer = encode_image(
+        img_buffer,
+        width as u32,
+        height as u32,
+        ExtendedColorType::Rgb8,
+        "png",
+    )?;
+    Ok((Status::Ok, (ContentType::PNG, output_buffer)))
+}
+
+/// Upscales `file` by `scale` (2, 4 or 8) and returns the result as PNG.
+/// `scale` can be sent as a form field or a query parameter.
+#[post("/upscale?<scale>", format = "multipart/form-data", data = "<data>")]
+pub async fn upscale(
+    content_type: &ContentType,
+    data: Data<'_>,
+    scale: Option<&str>,
+    pool: &State<InferencePool>,
+) -> io::Result<(Status, (ContentType, Vec<u8>))> {
+    let form = parse_uploaded_form(content_type, data, &["file"], &["scale"]).await?;
+
+    let scale = form
+        .text("scale")
+        .or(scale)
+        .map(|scale| scale.trim().trim_start_matches(['x', 'X']).parse::<u32>())
+        .unwrap_or(Ok(DEFAULT_SCALE));
+    let scale = match scale {
+        Ok(scale) if SUPPORTED_SCALES.contains(&scale) => scale,
+        _ => {
+            let message = format!(
+                "Unsupported scale, expected one of: {}",
+                SUPPORTED_SCALES.map(|scale| scale.to_string()).join(", ")
+            );
+            return Ok(bad_request_response(&message));
+        }
+    };
+
+    match pool.run(move || upscale_image(form, scale)).await {
+        Ok(response) => response,
+        Err(error) => Ok(pool_error_response(&error)),
+    }
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![upscale]
+}
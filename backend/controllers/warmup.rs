@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::Instant;
+
+use crate::sessions::base::{MattingOptions, SessionError};
+use crate::sessions::inpaint::INPAINT_MODEL;
+use crate::sessions::pool::SessionPool;
+use crate::sessions::realesrgan::{upscale_model_name, SUPPORTED_SCALES};
+use crate::sessions::registry::{DEFAULT_MODEL, MODEL_REGISTRY};
+use crate::sessions::settings::SESSION_SETTINGS;
+use crate::utils::config::{ConfigCell, ConfigSection};
+use image::{DynamicImage, GrayImage, Luma};
+use once_cell::sync::{Lazy, OnceCell};
+use rocket::fairing::AdHoc;
+use rocket::serde::{Deserialize, Serialize};
+
+use super::inpaint::{get_inpaint_session, INPAINT_SESSION};
+use super::models::known_models;
+use super::upscale::{get_upscale_session, upscale_session_cell};
+
+/// `[default.warmup]` section of Rocket.toml.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+pub struct WarmupConfig {
+    /// Load models on their first request instead of at startup.
+    pub lazy: bool,
+    /// Models to load at startup. Empty loads the default model.
+    pub models: Vec<String>,
+}
+
+impl ConfigSection for WarmupConfig {
+    const SECTION: &'static str = "warmup";
+
+    /// Every listed model has to exist.
+    fn validate(&self) -> Result<(), String> {
+        let known_models = known_models();
+        match self
+            .models()
+            .into_iter()
+            .find(|model| !known_models.contains(model))
+        {
+            Some(model) => Err(format!(
+                "unknown model {}, expected one of: {}",
+                model,
+                known_models.join(", ")
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `[default.warmup]`, validated at ignite.
+pub static WARMUP_CONFIG: ConfigCell<WarmupConfig> = ConfigCell::new();
+
+impl WarmupConfig {
+    /// The models to warm up, lowercased.
+    fn models(&self) -> Vec<String> {
+        if self.models.is_empty() {
+            return vec![DEFAULT_MODEL.to_owned()];
+        }
+
+        self.models
+            .iter()
+            .map(|model| model.trim().to_lowercase())
+            .collect()
+    }
+}
+
+/// Outcome of warming up one model.
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ModelWarmup {
+    pub ok: bool,
+    pub elapsed_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct WarmupState {
+    /// Warm-up has finished and every model loaded, or it was skipped.
+    pub ready: bool,
+    /// Warm-up has finished, whether or not every model loaded.
+    pub finished: bool,
+    pub lazy: bool,
+    pub models: BTreeMap<String, ModelWarmup>,
+}
+
+pub(crate) static WARMUP_STATE: Lazy<RwLock<WarmupState>> =
+    Lazy::new(|| RwLock::new(WarmupState::default()));
+
+/// Whether every model loaded at startup, or warm-up was skipped in lazy
+/// mode.
+pub(crate) fn is_ready() -> bool {
+    WARMUP_STATE
+        .read()
+        .map(|state| state.ready)
+        .unwrap_or_else(|error| error.into_inner().ready)
+}
+
+fn gray_image(size: u32) -> DynamicImage {
+    DynamicImage::ImageLuma8(GrayImage::from_pixel(size, size, Luma([128])))
+}
+
+/// Runs `run` once on every session of `pool`, so that none of them serves
+/// its first request cold. The sessions are checked out of the pool while
+/// they warm up, so requests wait for them as for any other checkout.
+fn warm_up_pool<T>(
+    pool: &SessionPool<T>,
+    run: impl Fn(&T) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), String> {
+    pool.checkout_all()
+        .iter()
+        .try_for_each(|session| run(session).map_err(|error| error.to_string()))
+}
+
+/// The pool loaded into `cell`, or the error it failed to load with.
+fn loaded_pool<T>(
+    cell: &OnceCell<Result<SessionPool<T>, SessionError>>,
+) -> Result<&SessionPool<T>, String> {
+    match cell.get() {
+        Some(Ok(pool)) => Ok(pool),
+        Some(Err(error)) => Err(error.to_string()),
+        None => Err(SessionError::PredictError.to_string()),
+    }
+}
+
+/// Loads `model` and runs a dummy inference at its input size on every
+/// session of its pool.
+fn warm_up(model: &str) -> Result<(), String> {
+    if model == INPAINT_MODEL {
+        get_inpaint_session().map_err(|error| error.to_string())?;
+        return warm_up_pool(loaded_pool(&INPAINT_SESSION)?, |session| {
+            // An empty mask returns early, so mark the center as the hole.
+            let size = session.input_size;
+            let mask = GrayImage::from_fn(size, size, |x, y| {
+                let inside =
+                    (size / 4..size * 3 / 4).contains(&x) && (size / 4..size * 3 / 4).contains(&y);
+                Luma([if inside { 255 } else { 0 }])
+            });
+            session.run(gray_image(size), DynamicImage::ImageLuma8(mask))?;
+            Ok(())
+        });
+    }
+
+    if let Some(scale) = SUPPORTED_SCALES
+        .into_iter()
+        .find(|&scale| upscale_model_name(scale) == model)
+    {
+        get_upscale_session(scale).map_err(|error| error.to_string())?;
+        let cell = upscale_session_cell(scale).ok_or(SessionError::NotImplemented.to_string())?;
+        return warm_up_pool(loaded_pool(cell)?, |session| {
+            session.run(gray_image(session.tile_size))?;
+            Ok(())
+        });
+    }
+
+    let session_options = SESSION_SETTINGS
+        .get()
+        .session_options(model)
+        .map_err(|error| error.to_string())?;
+    MODEL_REGISTRY
+        .get(model, session_options)
+        .map_err(|error| error.to_string())?;
+    let pool = MODEL_REGISTRY
+        .entry(model)
+        .and_then(|entry| entry.pool())
+        .ok_or_else(|| SessionError::PredictError.to_string())?;
+
+    warm_up_pool(pool, |session| {
+        session.run(gray_image(session.input_size()), &MattingOptions::default())?;
+        Ok(())
+    })
+}
+
+/// Loads and warms up the configured models in the background once the
+/// server has launched, unless `[default.warmup] lazy` is set. `/ready`
+/// reports 503 until every model has been tried, and keeps doing so if any of
+/// them failed to load; those are reported there and keep returning their
+/// error on request, as in lazy mode.
+pub fn warmup_fairing() -> AdHoc {
+    AdHoc::on_liftoff("Model warm-up", |_| {
+        Box::pin(async {
+            let config = WARMUP_CONFIG.get();
+            if config.lazy {
+                log::info!("Lazy mode, models are loaded on their first request");
+                let mut state = WARMUP_STATE
+                    .write()
+                    .unwrap_or_else(|error| error.into_inner());
+                state.lazy = true;
+                state.ready = true;
+                state.finished = true;
+                return;
+            }
+
+            let models = config.models();
+            let warm_up_all = move || {
+                for model in models {
+                    log::info!("Warming up model {}", model);
+                    let started = Instant::now();
+                    let result = warm_up(&model);
+                    let elapsed_ms = started.elapsed().as_millis();
+
+                    match &result {
+                        Ok(()) => log::info!("Model {} warmed up in {} ms", model, elapsed_ms),
+                        Err(error) => log::warn!("Model {} failed to warm up: {}", model, error),
+                    }
+                    WARMUP_STATE
+                        .write()
+                        .unwrap_or_else(|error| error.into_inner())
+                        .models
+                        .insert(
+                            model,
+                            ModelWarmup {
+                                ok: result.is_ok(),
+                                elapsed_ms,
+                                error: result.err(),
+                            },
+                        );
+                }
+
+                let mut state = WARMUP_STATE
+                    .write()
+                    .unwrap_or_else(|error| error.into_inner());
+                state.finished = true;
+                state.ready = state.models.values().all(|model| model.ok);
+                if state.ready {
+                    log::info!("Model warm-up finished, ready");
+                } else {
+                    log::error!("Model warm-up finished, some models failed to load");
+                }
+            };
+
+            // Rocket only starts serving once every liftoff fairing has
+            // finished, so warm up in the background and keep `/health` and
+            // `/ready` answering in the meantime.
+            rocket::tokio::spawn(async move {
+                if let Err(error) = rocket::tokio::task::spawn_blocking(warm_up_all).await {
+                    log::error!("Model warm-up failed: {}", error);
+                }
+            });
+        })
+    })
+}
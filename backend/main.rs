@@ -1,11 +1,11 @@
 use dotenv::var;
+use image_matting::controllers;
+use image_matting::sessions::batcher::BATCH_CONFIG;
+use image_matting::sessions::pool::SESSION_POOL_CONFIG;
+use image_matting::utils::inference_pool::inference_pool_fairing;
 use rocket::http::Method;
 use rocket_cors::{AllowedOrigins, CorsOptions};
 
-mod controllers;
-mod sessions;
-mod utils;
-
 #[macro_use]
 extern crate rocket;
 
@@ -26,6 +26,18 @@ fn rocket() -> _ {
         )
         .allow_credentials(true);
 
-    let app = rocket::build().mount("/", controllers::rembg::routes());
+    let app = rocket::build()
+        .attach(inference_pool_fairing())
+        .attach(SESSION_POOL_CONFIG.fairing())
+        .attach(BATCH_CONFIG.fairing())
+        .attach(controllers::upscale::UPSCALE_CONFIG.fairing())
+        .attach(controllers::models::settings_fairing())
+        .attach(controllers::warmup::WARMUP_CONFIG.fairing())
+        .attach(controllers::warmup::warmup_fairing())
+        .mount("/", controllers::health::routes())
+        .mount("/", controllers::models::routes())
+        .mount("/", controllers::rembg::routes())
+        .mount("/", controllers::inpaint::routes())
+        .mount("/", controllers::upscale::routes());
     app.attach(cors.to_cors().unwrap())
 }
@@ -0,0 +1,573 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, GrayImage};
+use ndarray::{Array2, Array3, Axis, Zip};
+use ndarray_ndimage::{minimum_filter, BorderMode};
+
+use super::base::SessionError;
+
+/// Trimap values: certain background, certain foreground and the unknown band
+/// between them that the solver fills in.
+pub const TRIMAP_BACKGROUND: u8 = 0;
+pub const TRIMAP_UNKNOWN: u8 = 128;
+pub const TRIMAP_FOREGROUND: u8 = 255;
+
+/// Regularization of the color covariance of each window. Smaller values
+/// follow the colors more closely, larger ones give smoother alpha.
+const EPSILON: f64 = 1e-6;
+
+/// The solver stops once the residual is this small relative to the right
+/// hand side, far below what survives quantization to 8 bits, or after
+/// `MAX_ITERATIONS`.
+const TOLERANCE: f64 = 1e-4;
+const MAX_ITERATIONS: usize = 500;
+
+/// Most unknown pixels solved for at full resolution. Larger bands are
+/// solved on a downscaled copy of the image and upscaled, as the solver time
+/// grows faster than the band.
+const MAX_UNKNOWN_PIXELS: usize = 200_000;
+
+/// Pixels per 3x3 window of the matting Laplacian.
+const WINDOW_PIXELS: f64 = 9.0;
+
+/// How the trimap is built from the predicted mask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlphaMattingOptions {
+    /// Mask values above this are foreground.
+    pub foreground_threshold: u8,
+    /// Mask values below this are background.
+    pub background_threshold: u8,
+    /// Size of the square both regions are eroded by, which widens the
+    /// unknown band around the edge.
+    pub erode_size: u32,
+}
+
+impl Default for AlphaMattingOptions {
+    /// The defaults of rembg's `alpha_matting`.
+    fn default() -> Self {
+        Self {
+            foreground_threshold: 240,
+            background_threshold: 10,
+            erode_size: 10,
+        }
+    }
+}
+
+/// Builds a trimap from `mask`: pixels that stay foreground or background
+/// after eroding each region by `erode_size` are certain, everything else is
+/// unknown.
+pub fn trimap(mask: &Array2<u8>, options: &AlphaMattingOptions) -> Array2<u8> {
+    let foreground = mask.mapv(|value| (value > options.foreground_threshold) as u8);
+    let background = mask.mapv(|value| (value < options.background_threshold) as u8);
+
+    // As in rembg, the foreground shrinks away from the image border while
+    // the background extends past it.
+    let erode = |region: Array2<u8>, border: u8| match options.erode_size {
+        0 | 1 => region,
+        size => minimum_filter(&region, size as usize, BorderMode::Constant(border), 0),
+    };
+    let foreground = erode(foreground, 0);
+    let background = erode(background, 1);
+
+    Zip::from(&foreground)
+        .and(&background)
+        .map_collect(|&foreground, &background| {
+            if foreground == 1 {
+                TRIMAP_FOREGROUND
+            } else if background == 1 {
+                TRIMAP_BACKGROUND
+            } else {
+                TRIMAP_UNKNOWN
+            }
+        })
+}
+
+/// Refines the `H x W x 1` `mask` predicted for `image` with closed-form
+/// matting (Levin et al., "A Closed-Form Solution to Natural Image Matting").
+///
+/// The certain regions of the trimap are kept and alpha in the unknown band
+/// is solved for so that, in every 3x3 window, it is an affine function of
+/// the image colors. That recovers soft edges such as hair and fur from the
+/// colors where the network only gives a blotchy outline. Bands of more
+/// than `MAX_UNKNOWN_PIXELS` pixels are solved at a lower resolution.
+pub fn refine(
+    image: &DynamicImage,
+    mask: Array3<u8>,
+    options: &AlphaMattingOptions,
+) -> Result<Array3<u8>, SessionError> {
+    let (width, height) = image.dimensions();
+    let (mask_height, mask_width, _) = mask.dim();
+    if (mask_width as u32, mask_height as u32) != (width, height) {
+        log::error!(
+            "Mask size {}x{} does not match image size {}x{}",
+            mask_width,
+            mask_height,
+            width,
+            height
+        );
+        return Err(SessionError::ImageProcessingError);
+    }
+
+    let mask = mask.index_axis_move(Axis(2), 0);
+    let trimap = trimap(&mask, options);
+    let unknown = count_unknown(&trimap);
+    if unknown <= MAX_UNKNOWN_PIXELS {
+        return Ok(solve_alpha(image, &mask, trimap).insert_axis(Axis(2)));
+    }
+
+    // Solve a band of at most `MAX_UNKNOWN_PIXELS` on a downscaled copy and
+    // keep the certain regions of the full-resolution trimap.
+    let scale = (MAX_UNKNOWN_PIXELS as f64 / unknown as f64).sqrt();
+    let small_width = ((width as f64 * scale).round() as u32).max(3);
+    let small_height = ((height as f64 * scale).round() as u32).max(3);
+    log::warn!(
+        "{} unknown pixels exceed the limit of {}, solving alpha at {}x{} instead of {}x{}",
+        unknown,
+        MAX_UNKNOWN_PIXELS,
+        small_width,
+        small_height,
+        width,
+        height
+    );
+    let small_image = image.resize_exact(small_width, small_height, FilterType::Triangle);
+    let small_mask = resize_mask(&mask, small_width, small_height);
+    let small_options = AlphaMattingOptions {
+        erode_size: (options.erode_size as f64 * scale).round() as u32,
+        ..*options
+    };
+    let small_trimap = self::trimap(&small_mask, &small_options);
+    let alpha = resize_mask(
+        &solve_alpha(&small_image, &small_mask, small_trimap),
+        width,
+        height,
+    );
+
+    let refined = Zip::from(&trimap)
+        .and(&alpha)
+        .map_collect(|&trimap, &alpha| {
+            if trimap == TRIMAP_UNKNOWN {
+                alpha
+            } else {
+                trimap
+            }
+        });
+    Ok(refined.insert_axis(Axis(2)))
+}
+
+fn count_unknown(trimap: &Array2<u8>) -> usize {
+    trimap
+        .iter()
+        .filter(|&&value| value == TRIMAP_UNKNOWN)
+        .count()
+}
+
+fn resize_mask(mask: &Array2<u8>, width: u32, height: u32) -> Array2<u8> {
+    let (mask_height, mask_width) = mask.dim();
+    let mask = GrayImage::from_raw(
+        mask_width as u32,
+        mask_height as u32,
+        mask.iter().copied().collect(),
+    )
+    .unwrap_or_default();
+    let resized = imageops::resize(&mask, width, height, FilterType::Triangle);
+    Array2::from_shape_vec((height as usize, width as usize), resized.into_raw())
+        .unwrap_or_else(|_| Array2::zeros((height as usize, width as usize)))
+}
+
+/// Solves for alpha in the unknown band of `trimap`, built from `mask` for
+/// `image` of the same size. Returns `mask` unchanged when there is nothing
+/// to solve or nothing to solve it from.
+fn solve_alpha(image: &DynamicImage, mask: &Array2<u8>, trimap: Array2<u8>) -> Array2<u8> {
+    let (width, height) = image.dimensions();
+    let unknown = count_unknown(&trimap);
+    if unknown == 0 || unknown == trimap.len() || width < 3 || height < 3 {
+        return mask.clone();
+    }
+
+    log::info!(
+        "Solving alpha for {} unknown pixels of a {}x{} image",
+        unknown,
+        width,
+        height
+    );
+    let colors = image
+        .to_rgb8()
+        .pixels()
+        .map(|pixel| pixel.0.map(|channel| channel as f32 / 255.0))
+        .collect::<Vec<_>>();
+    let problem = MattingProblem::new(&colors, &trimap, width as usize, height as usize);
+    let position = |pixel: usize| [pixel / width as usize, pixel % width as usize];
+    let initial = problem
+        .unknown_pixels
+        .iter()
+        .map(|&pixel| mask[position(pixel)] as f64 / 255.0)
+        .collect::<Vec<_>>();
+    let alpha = problem.solve(initial);
+
+    let mut refined = trimap.clone();
+    for (&pixel, &alpha) in problem.unknown_pixels.iter().zip(&alpha) {
+        refined[position(pixel)] = (alpha * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+    refined
+}
+
+/// A 3x3 window of the matting Laplacian: its mean color and the inverse of
+/// its regularized color covariance, stored as the upper triangle.
+struct Window {
+    center: usize,
+    mean: [f32; 3],
+    inverse: [f32; 6],
+}
+
+impl Window {
+    /// `(I - mean)^T inverse v` for the color `I` of one of its pixels.
+    fn weight(&self, color: &[f32; 3], v: &[f64; 3]) -> f64 {
+        let d = [
+            (color[0] - self.mean[0]) as f64,
+            (color[1] - self.mean[1]) as f64,
+            (color[2] - self.mean[2]) as f64,
+        ];
+        let [a, b, c, e, f, i] = self.inverse.map(|value| value as f64);
+        d[0] * (a * v[0] + b * v[1] + c * v[2])
+            + d[1] * (b * v[0] + e * v[1] + f * v[2])
+            + d[2] * (c * v[0] + f * v[1] + i * v[2])
+    }
+}
+
+/// The matting Laplacian restricted to the unknown pixels, `L_uu`, applied
+/// without building the matrix. Only windows that touch an unknown pixel are
+/// kept, so memory scales with the unknown band rather than the image.
+struct MattingProblem<'a> {
+    colors: &'a [[f32; 3]],
+    trimap: &'a [u8],
+    width: usize,
+    unknown_pixels: Vec<usize>,
+    /// Index of each pixel in `unknown_pixels`, `usize::MAX` for known ones.
+    unknown_index: Vec<usize>,
+    windows: Vec<Window>,
+}
+
+impl<'a> MattingProblem<'a> {
+    fn new(colors: &'a [[f32; 3]], trimap: &'a Array2<u8>, width: usize, height: usize) -> Self {
+        let trimap = trimap.as_slice().unwrap_or_default();
+        let mut unknown_pixels = Vec::new();
+        let mut unknown_index = vec![usize::MAX; trimap.len()];
+        for (pixel, &value) in trimap.iter().enumerate() {
+            if value == TRIMAP_UNKNOWN {
+                unknown_index[pixel] = unknown_pixels.len();
+                unknown_pixels.push(pixel);
+            }
+        }
+
+        let mut problem = Self {
+            colors,
+            trimap,
+            width,
+            unknown_pixels,
+            unknown_index,
+            windows: Vec::new(),
+        };
+        problem.windows = (1..height - 1)
+            .flat_map(|y| (1..width - 1).map(move |x| y * width + x))
+            .filter(|&center| {
+                problem
+                    .neighbours(center)
+                    .any(|pixel| problem.unknown_index[pixel] != usize::MAX)
+            })
+            .map(|center| problem.window(center))
+            .collect();
+        problem
+    }
+
+    fn neighbours(&self, center: usize) -> impl Iterator<Item = usize> {
+        let width = self.width;
+        [center - width, center, center + width]
+            .into_iter()
+            .flat_map(|row| [row - 1, row, row + 1])
+    }
+
+    fn window(&self, center: usize) -> Window {
+        let mut sum = [0.0_f64; 3];
+        let mut products = [0.0_f64; 6];
+        for pixel in self.neighbours(center) {
+            let [r, g, b] = self.colors[pixel].map(|channel| channel as f64);
+            sum[0] += r;
+            sum[1] += g;
+            sum[2] += b;
+            products[0] += r * r;
+            products[1] += r * g;
+            products[2] += r * b;
+            products[3] += g * g;
+            products[4] += g * b;
+            products[5] += b * b;
+        }
+
+        let mean = sum.map(|sum| sum / WINDOW_PIXELS);
+        let regularization = EPSILON / WINDOW_PIXELS;
+        let covariance = |index: usize, i: usize, j: usize| {
+            products[index] / WINDOW_PIXELS - mean[i] * mean[j]
+                + if i == j { regularization } else { 0.0 }
+        };
+        let [a, b, c, e, f, i] = [
+            covariance(0, 0, 0),
+            covariance(1, 0, 1),
+            covariance(2, 0, 2),
+            covariance(3, 1, 1),
+            covariance(4, 1, 2),
+            covariance(5, 2, 2),
+        ];
+
+        // Inverse of the symmetric matrix [[a, b, c], [b, e, f], [c, f, i]].
+        let cofactors = [
+            e * i - f * f,
+            c * f - b * i,
+            b * f - c * e,
+            a * i - c * c,
+            b * c - a * f,
+            a * e - b * b,
+        ];
+        let determinant = a * cofactors[0] + b * cofactors[1] + c * cofactors[2];
+        Window {
+            center,
+            mean: mean.map(|mean| mean as f32),
+            inverse: cofactors.map(|cofactor| (cofactor / determinant) as f32),
+        }
+    }
+
+    /// Adds `L x` to `output` for the unknown pixels, where `value` gives `x`
+    /// at any pixel.
+    fn apply(&self, value: impl Fn(usize) -> f64, output: &mut [f64]) {
+        for window in &self.windows {
+            let mut sum = 0.0;
+            let mut weighted = [0.0_f64; 3];
+            for pixel in self.neighbours(window.center) {
+                let value = value(pixel);
+                if value == 0.0 {
+                    continue;
+                }
+                let color = &self.colors[pixel];
+                sum += value;
+                for channel in 0..3 {
+                    weighted[channel] += (color[channel] - window.mean[channel]) as f64 * value;
+                }
+            }
+
+            for pixel in self.neighbours(window.center) {
+                let index = self.unknown_index[pixel];
+                if index != usize::MAX {
+                    output[index] += value(pixel)
+                        - (sum + window.weight(&self.colors[pixel], &weighted)) / WINDOW_PIXELS;
+                }
+            }
+        }
+    }
+
+    /// `x` at any pixel for the values `values` of the unknown pixels, zero at
+    /// the known ones.
+    fn unknown_values<'b>(&'b self, values: &'b [f64]) -> impl Fn(usize) -> f64 + 'b {
+        move |pixel| match self.unknown_index[pixel] {
+            usize::MAX => 0.0,
+            index => values[index],
+        }
+    }
+
+    /// The diagonal of `L_uu`, used as the preconditioner.
+    fn diagonal(&self) -> Vec<f64> {
+        let mut diagonal = vec![0.0; self.unknown_pixels.len()];
+        for window in &self.windows {
+            for pixel in self.neighbours(window.center) {
+                let index = self.unknown_index[pixel];
+                if index != usize::MAX {
+                    let color = &self.colors[pixel];
+                    let d = [0, 1, 2].map(|channel| (color[channel] - window.mean[channel]) as f64);
+                    diagonal[index] += 1.0 - (1.0 + window.weight(color, &d)) / WINDOW_PIXELS;
+                }
+            }
+        }
+        diagonal
+    }
+
+    /// Solves `L_uu alpha_u = -L_uk alpha_k` with the Jacobi-preconditioned
+    /// conjugate gradient method, starting from `alpha`. Unknown regions no
+    /// window connects to a known pixel keep their starting value.
+    fn solve(&self, mut alpha: Vec<f64>) -> Vec<f64> {
+        let known = |pixel: usize| match self.trimap[pixel] {
+            TRIMAP_FOREGROUND => 1.0,
+            _ => 0.0,
+        };
+        let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>();
+
+        let mut right_hand_side = vec![0.0; alpha.len()];
+        self.apply(
+            |pixel| match self.unknown_index[pixel] {
+                usize::MAX => known(pixel),
+                _ => 0.0,
+            },
+            &mut right_hand_side,
+        );
+        right_hand_side
+            .iter_mut()
+            .for_each(|value| *value = -*value);
+
+        let preconditioner = self
+            .diagonal()
+            .into_iter()
+            .map(|diagonal| {
+                if diagonal > 1e-12 {
+                    1.0 / diagonal
+                } else {
+                    0.0
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut product = vec![0.0; alpha.len()];
+        self.apply(self.unknown_values(&alpha), &mut product);
+        let mut residual = right_hand_side
+            .iter()
+            .zip(&product)
+            .map(|(b, product)| b - product)
+            .collect::<Vec<_>>();
+        let mut preconditioned = residual
+            .iter()
+            .zip(&preconditioner)
+            .map(|(residual, preconditioner)| residual * preconditioner)
+            .collect::<Vec<_>>();
+        let mut direction = preconditioned.clone();
+        let mut rho = dot(&residual, &preconditioned);
+        let threshold = TOLERANCE * TOLERANCE * dot(&right_hand_side, &right_hand_side).max(1e-12);
+
+        let mut iterations = 0;
+        while iterations < MAX_ITERATIONS && dot(&residual, &residual) > threshold {
+            product.iter_mut().for_each(|value| *value = 0.0);
+            self.apply(self.unknown_values(&direction), &mut product);
+            let curvature = dot(&direction, &product);
+            if curvature <= 0.0 || rho == 0.0 {
+                break;
+            }
+
+            let step = rho / curvature;
+            for index in 0..alpha.len() {
+                alpha[index] += step * direction[index];
+                residual[index] -= step * product[index];
+                preconditioned[index] = residual[index] * preconditioner[index];
+            }
+
+            let next_rho = dot(&residual, &preconditioned);
+            let beta = next_rho / rho;
+            rho = next_rho;
+            for (direction, preconditioned) in direction.iter_mut().zip(&preconditioned) {
+                *direction = preconditioned + beta * *direction;
+            }
+            iterations += 1;
+        }
+
+        log::debug!("Closed-form matting took {} iterations", iterations);
+        alpha
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+    use ndarray::Array2;
+
+    #[test]
+    fn trimap_erodes_both_regions() {
+        // Foreground in the left half, background in the right half.
+        let mask = Array2::from_shape_fn((5, 8), |(_, x)| if x < 4 { 255 } else { 0 });
+        let options = AlphaMattingOptions {
+            erode_size: 3,
+            ..Default::default()
+        };
+        let trimap = trimap(&mask, &options);
+
+        assert_eq!(trimap[[2, 1]], TRIMAP_FOREGROUND);
+        assert_eq!(trimap[[2, 5]], TRIMAP_BACKGROUND);
+        // Both sides of the edge become unknown.
+        assert_eq!(trimap[[2, 3]], TRIMAP_UNKNOWN);
+        assert_eq!(trimap[[2, 4]], TRIMAP_UNKNOWN);
+        // The foreground shrinks away from the image border, the background
+        // does not.
+        assert_eq!(trimap[[2, 0]], TRIMAP_UNKNOWN);
+        assert_eq!(trimap[[0, 1]], TRIMAP_UNKNOWN);
+        assert_eq!(trimap[[0, 7]], TRIMAP_BACKGROUND);
+    }
+
+    #[test]
+    fn trimap_without_erosion_only_thresholds() {
+        let mask = Array2::from_shape_vec((1, 4), vec![0, 10, 128, 250]).unwrap();
+        let trimap = trimap(
+            &mask,
+            &AlphaMattingOptions {
+                erode_size: 0,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            trimap.into_raw_vec(),
+            vec![
+                TRIMAP_BACKGROUND,
+                TRIMAP_UNKNOWN,
+                TRIMAP_UNKNOWN,
+                TRIMAP_FOREGROUND
+            ]
+        );
+    }
+
+    #[test]
+    fn refine_recovers_a_soft_edge_between_two_colors() {
+        let (width, height) = (32, 24);
+        // Colors whose blends along the ramp are exact in 8 bits, as rounding
+        // noise is not regularized away by the small epsilon.
+        let foreground = [232.0, 48.0, 24.0];
+        let background = [24.0, 80.0, 208.0];
+        // Alpha ramps from 1 to 0 over columns 12 to 20.
+        let alpha = |x: u32| (1.0 - (x as f32 - 12.0) / 8.0).clamp(0.0, 1.0);
+        let image = RgbImage::from_fn(width, height, |x, _| {
+            let alpha = alpha(x);
+            Rgb([0, 1, 2].map(|channel| {
+                (alpha * foreground[channel] + (1.0 - alpha) * background[channel]).round() as u8
+            }))
+        });
+        // The predicted mask is a hard step in the middle of the ramp.
+        let mask = Array3::from_shape_fn((height as usize, width as usize, 1), |(_, x, _)| {
+            if x < 16 {
+                255
+            } else {
+                0
+            }
+        });
+        let options = AlphaMattingOptions {
+            erode_size: 9,
+            ..Default::default()
+        };
+
+        let refined = refine(&DynamicImage::ImageRgb8(image), mask, &options).unwrap();
+
+        // Away from the border the foreground stays certain, so check the
+        // middle row.
+        for x in 0..width {
+            let expected = alpha(x) * 255.0;
+            let actual = refined[[height as usize / 2, x as usize, 0]] as f32;
+            assert!(
+                (actual - expected).abs() <= 12.0,
+                "column {}: expected {}, got {}",
+                x,
+                expected,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn refine_keeps_a_mask_without_unknown_pixels() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(6, 6));
+        let mask = Array3::from_elem((6, 6, 1), 255);
+
+        let refined = refine(&image, mask.clone(), &AlphaMattingOptions::default()).unwrap();
+
+        assert_eq!(refined, mask);
+    }
+}
@@ -1,10 +1,25 @@
 use image::DynamicImage;
+use ndarray::{Array4, ArrayViewD, Axis};
+use rocket::serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
 use ort::{
-    CPUExecutionProvider, CUDAExecutionProvider, CoreMLExecutionProvider,
-    ExecutionProviderDispatch, GraphOptimizationLevel, Session,
+    ArenaExtendStrategy, CPUExecutionProvider, CUDAExecutionProvider,
+    CUDAExecutionProviderCuDNNConvAlgoSearch, CoreMLExecutionProvider, ExecutionProvider,
+    ExecutionProviderDispatch, GraphOptimizationLevel, OpenVINOExecutionProvider, Session,
+    XNNPACKExecutionProvider,
 };
 
-#[derive(Debug, Clone)]
+use super::descriptor::ResizeMode;
+use super::fusion::{confidence_weighted_mean, fuse, FusionMode};
+use super::metadata::ModelMetadata;
+use super::tiling::refine_tiled;
+use super::tta::{Augmentation, TtaMode};
+use super::upsample::Upsampler;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
 pub enum OptLevel {
     Disable,
     Level1,
@@ -12,12 +27,28 @@ pub enum OptLevel {
     Level3,
 }
 
+/// How a memory arena grows when it runs out of space.
+#[derive(Debug, Clone)]
+pub enum ArenaStrategy {
+    NextPowerOfTwo,
+    SameAsRequested,
+}
+
+/// Execution provider names accepted by [`SessionOptions::with_providers`].
+pub const PROVIDERS: [&str; 5] = ["cpu", "cuda", "coreml", "openvino", "xnnpack"];
+
 #[derive(Debug, Clone)]
 pub struct SessionOptions {
     opt_level: Option<OptLevel>,
     num_threads: usize,
+    inter_threads: usize,
     parallel_execution: bool,
     memory_pattern: bool,
+    cpu_arena: bool,
+    arena_strategy: Option<ArenaStrategy>,
+    gpu_mem_limit: Option<usize>,
+    deterministic: bool,
+    strict_providers: bool,
     providers: Option<Vec<String>>,
 }
 
@@ -25,10 +56,16 @@ impl Default for SessionOptions {
     fn default() -> Self {
         Self {
             opt_level: Some(OptLevel::Level3),
-            num_threads: 32,
+            num_threads: 0,
+            inter_threads: 0,
             parallel_execution: true,
             memory_pattern: true,
-            providers: Some(vec!["coreml".to_owned()]),
+            cpu_arena: true,
+            arena_strategy: None,
+            gpu_mem_limit: None,
+            deterministic: false,
+            strict_providers: false,
+            providers: None,
         }
     }
 }
@@ -43,11 +80,23 @@ impl SessionOptions {
         self
     }
 
+    /// Intra-op threads, `0` leaves the onnxruntime default.
     pub fn with_num_threads(&mut self, num_threads: usize) -> &mut Self {
         self.num_threads = num_threads;
         self
     }
 
+    pub fn num_threads(&self) -> usize {
+        self.num_threads
+    }
+
+    /// Threads used to run independent graph nodes in parallel, `0` leaves the
+    /// onnxruntime default.
+    pub fn with_inter_threads(&mut self, inter_threads: usize) -> &mut Self {
+        self.inter_threads = inter_threads;
+        self
+    }
+
     pub fn with_parallel_execution(&mut self, parallel_execution: bool) -> &mut Self {
         self.parallel_execution = parallel_execution;
         self
@@ -58,21 +107,181 @@ impl SessionOptions {
         self
     }
 
+    /// Whether the CPU provider allocates from a memory arena.
+    pub fn with_cpu_arena(&mut self, cpu_arena: bool) -> &mut Self {
+        self.cpu_arena = cpu_arena;
+        self
+    }
+
+    /// Growth strategy of the CUDA memory arena.
+    pub fn with_arena_strategy(&mut self, arena_strategy: ArenaStrategy) -> &mut Self {
+        self.arena_strategy = Some(arena_strategy);
+        self
+    }
+
+    /// Size limit of the CUDA memory arena in bytes.
+    pub fn with_gpu_mem_limit(&mut self, gpu_mem_limit: usize) -> &mut Self {
+        self.gpu_mem_limit = Some(gpu_mem_limit);
+        self
+    }
+
+    /// Trades speed for reproducible results: the graph runs sequentially and
+    /// CUDA always picks the same convolution algorithms. onnxruntime's own
+    /// `SetDeterministicCompute` switch is not exposed by ort 2.0.0-rc.4.
+    pub fn with_deterministic(&mut self, deterministic: bool) -> &mut Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Fail to load a model when one of the providers is unavailable, instead
+    /// of logging a warning and continuing with the remaining ones.
+    pub fn with_strict_providers(&mut self, strict_providers: bool) -> &mut Self {
+        self.strict_providers = strict_providers;
+        self
+    }
+
+    /// Execution providers in order of preference, see [`PROVIDERS`]. The CPU
+    /// provider is always tried last.
     pub fn with_providers(&mut self, providers: Vec<String>) -> &mut Self {
         self.providers = Some(providers);
         self
     }
 
     pub fn build(&self) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(provider) = self
+            .providers
+            .iter()
+            .flatten()
+            .find(|provider| !PROVIDERS.contains(&provider.as_str()))
+        {
+            return Err(Box::new(SessionError::ModelLoadError(format!(
+                "Unknown execution provider {}, expected one of: {}",
+                provider,
+                PROVIDERS.join(", ")
+            ))));
+        }
+
         Ok(self.clone())
     }
+
+    /// Builds the execution providers, checking that onnxruntime supports each
+    /// of them on this machine.
+    fn execution_providers(&self) -> Result<Vec<ExecutionProviderDispatch>, SessionError> {
+        let mut providers = self.providers.clone().unwrap_or_default();
+        if !providers.iter().any(|provider| provider == "cpu") {
+            providers.push("cpu".to_owned());
+        }
+
+        let mut dispatches = Vec::new();
+        for provider in providers {
+            let dispatch = match provider.as_str() {
+                "cpu" => {
+                    let mut cpu = CPUExecutionProvider::default();
+                    if self.cpu_arena {
+                        cpu = cpu.with_arena_allocator();
+                    }
+                    self.check_provider(&provider, cpu)?
+                }
+                "cuda" => {
+                    let mut cuda = CUDAExecutionProvider::default();
+                    if let Some(arena_strategy) = &self.arena_strategy {
+                        cuda = cuda.with_arena_extend_strategy(match arena_strategy {
+                            ArenaStrategy::NextPowerOfTwo => ArenaExtendStrategy::NextPowerOfTwo,
+                            ArenaStrategy::SameAsRequested => ArenaExtendStrategy::SameAsRequested,
+                        });
+                    }
+                    if let Some(gpu_mem_limit) = self.gpu_mem_limit {
+                        cuda = cuda.with_memory_limit(gpu_mem_limit);
+                    }
+                    if self.deterministic {
+                        cuda = cuda.with_conv_algorithm_search(
+                            CUDAExecutionProviderCuDNNConvAlgoSearch::Default,
+                        );
+                    }
+                    self.check_provider(&provider, cuda)?
+                }
+                "coreml" => self.check_provider(&provider, CoreMLExecutionProvider::default())?,
+                "openvino" => self.check_provider(
+                    &provider,
+                    OpenVINOExecutionProvider::default()
+                        .with_device_type("CPU")
+                        .with_num_threads(self.num_threads),
+                )?,
+                "xnnpack" => {
+                    let mut xnnpack = XNNPACKExecutionProvider::default();
+                    if let Some(num_threads) = std::num::NonZeroUsize::new(self.num_threads) {
+                        xnnpack = xnnpack.with_intra_op_num_threads(num_threads);
+                    }
+                    self.check_provider(&provider, xnnpack)?
+                }
+                _ => {
+                    return Err(SessionError::ModelLoadError(format!(
+                        "Unknown execution provider {}, expected one of: {}",
+                        provider,
+                        PROVIDERS.join(", ")
+                    )))
+                }
+            };
+            dispatches.extend(dispatch);
+        }
+
+        Ok(dispatches)
+    }
+
+    /// Returns the provider if onnxruntime can use it here. Otherwise fails in
+    /// strict mode, or logs a warning and skips it.
+    fn check_provider<E>(
+        &self,
+        name: &str,
+        provider: E,
+    ) -> Result<Option<ExecutionProviderDispatch>, SessionError>
+    where
+        E: ExecutionProvider + Into<ExecutionProviderDispatch>,
+    {
+        let reason = if !provider.supported_by_platform() {
+            Some("it is not supported on this platform".to_owned())
+        } else {
+            match provider.is_available() {
+                Ok(true) => None,
+                Ok(false) => Some(format!(
+                    "onnxruntime was built without {}",
+                    provider.as_str()
+                )),
+                Err(error) => Some(error.to_string()),
+            }
+        };
+
+        match reason {
+            None => {
+                let dispatch = provider.into();
+                Ok(Some(if self.strict_providers {
+                    dispatch.error_on_failure()
+                } else {
+                    dispatch
+                }))
+            }
+            Some(reason) if self.strict_providers => Err(SessionError::ModelLoadError(format!(
+                "Execution provider {} is unavailable: {}",
+                name, reason
+            ))),
+            Some(reason) => {
+                log::warn!(
+                    "Execution provider {} is unavailable and will be skipped: {}",
+                    name,
+                    reason
+                );
+                Ok(None)
+            }
+        }
+    }
 }
 
 pub struct BaseSession {
-    pub(crate) debug: bool,
+    pub debug: bool,
     pub(crate) inner_session: Option<ort::Session>,
     pub session_options: SessionOptions,
     pub model_path: String,
+    pub metadata: ModelMetadata,
 }
 
 impl BaseSession {
@@ -80,14 +289,72 @@ impl BaseSession {
         debug: bool,
         session_options: SessionOptions,
         model_name: &str,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
+    ) -> Result<Self, SessionError> {
+        let model_path = find_model_file(model_name)?;
+        Self::from_path(debug, session_options, &model_path)
+    }
+
+    /// Creates a session from an explicit model file instead of searching by name.
+    pub fn from_path(
+        debug: bool,
+        session_options: SessionOptions,
+        model_path: &Path,
+    ) -> Result<Self, SessionError> {
         let session_opts = session_options.clone();
         log::debug!("Session created with options: {:?}", &session_opts);
 
-        let project_dir = std::env!("CARGO_MANIFEST_DIR");
-        let model_path = format!("{}/models/onnx/{}.onnx", project_dir, model_name);
+        let model_path = model_path.display().to_string();
 
         log::debug!("Model path: {}", &model_path);
+        let providers = session_options.execution_providers()?;
+        log::info!(
+            "Loading {} with providers: {:?}",
+            model_path,
+            providers
+                .iter()
+                .map(|provider| format!("{:?}", provider))
+                .collect::<Vec<_>>()
+        );
+        let session =
+            Self::build_session(session_options, providers, &model_path).map_err(|error| {
+                SessionError::ModelLoadError(format!("Failed to load {}: {}", model_path, error))
+            })?;
+
+        log::debug!("Session: {:?}", &session);
+
+        let metadata = ModelMetadata::from_session(&session);
+        log::info!("Model {}: {}", model_path, metadata);
+
+        Ok(Self {
+            debug,
+            session_options: session_opts,
+            inner_session: Some(session),
+            model_path: model_path.to_owned(),
+            metadata,
+        })
+    }
+
+    /// Runs `check` against the model's inputs and outputs, adding the model
+    /// file and its full signature to any error, so that an unexpected export
+    /// fails at load time instead of on the first request.
+    pub fn validate(
+        &self,
+        check: impl FnOnce(&ModelMetadata) -> Result<(), SessionError>,
+    ) -> Result<(), SessionError> {
+        check(&self.metadata).map_err(|error| match error {
+            SessionError::ModelLoadError(message) => SessionError::ModelLoadError(format!(
+                "Invalid model {}: {} ({})",
+                self.model_path, message, self.metadata
+            )),
+            error => error,
+        })
+    }
+
+    fn build_session(
+        session_options: SessionOptions,
+        providers: Vec<ExecutionProviderDispatch>,
+        model_path: &str,
+    ) -> ort::Result<Session> {
         let mut session_builder = Session::builder()?;
 
         if let Some(opt_level) = session_options.opt_level {
@@ -99,37 +366,174 @@ impl BaseSession {
             })?;
         }
 
-        session_builder =
-            session_builder.with_intra_threads(if session_options.num_threads <= 0 {
-                1
-            } else {
-                session_options.num_threads
-            })?;
+        if session_options.num_threads > 0 {
+            session_builder = session_builder.with_intra_threads(session_options.num_threads)?;
+        }
 
-        let prividers = session_options
-            .providers
-            .unwrap_or(vec!["coreml".to_owned()])
-            .iter()
-            .map(|provider| match provider.as_str() {
-                "coreml" => CoreMLExecutionProvider::default().build(),
-                "cuda" => CUDAExecutionProvider::default().build(),
-                _ => CPUExecutionProvider::default().build(),
-            })
-            .collect::<Vec<ExecutionProviderDispatch>>();
-        session_builder = session_builder.with_execution_providers(prividers)?;
+        // Parallel execution schedules independent nodes in whatever order their
+        // inputs become ready, so deterministic runs stay sequential.
+        let parallel_execution =
+            session_options.parallel_execution && !session_options.deterministic;
+        session_builder = session_builder
+            .with_parallel_execution(parallel_execution)?
+            .with_memory_pattern(session_options.memory_pattern)?;
+
+        if session_options.inter_threads > 0 {
+            session_builder = session_builder.with_inter_threads(session_options.inter_threads)?;
+        }
+
+        session_builder = session_builder.with_execution_providers(providers)?;
 
         log::debug!("Session builder: starting to build session");
-        let session = session_builder.commit_from_file(model_path.clone())?;
+        session_builder.commit_from_file(model_path)
+    }
+}
 
-        log::debug!("Session: {:?}", &session);
+/// Directories searched for `{model_name}.onnx`, in order:
+///
+/// 1. `MODEL_DIR` env var, which may hold several paths joined by the platform
+///    path separator (`:` on Unix, `;` on Windows);
+/// 2. `model_dirs` (a list) or `model_dir` (a single path) in Rocket.toml, which
+///    can also be set through `ROCKET_MODEL_DIRS` / `ROCKET_MODEL_DIR`;
+/// 3. `models/onnx` and `models` under the working directory and next to the
+///    executable.
+pub fn model_search_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
 
-        Ok(Self {
-            debug,
-            session_options: session_opts,
-            inner_session: Some(session),
-            model_path: model_path.to_owned(),
+    if let Ok(model_dir) = dotenv::var("MODEL_DIR") {
+        paths.extend(std::env::split_paths(&model_dir).filter(|path| !path.as_os_str().is_empty()));
+    }
+
+    let figment = rocket::Config::figment();
+    if let Ok(model_dirs) = figment.extract_inner::<Vec<PathBuf>>("model_dirs") {
+        paths.extend(model_dirs);
+    }
+    if let Ok(model_dir) = figment.extract_inner::<PathBuf>("model_dir") {
+        paths.push(model_dir);
+    }
+
+    let mut base_dirs = vec![PathBuf::from(".")];
+    if let Some(exe_dir) = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(PathBuf::from))
+    {
+        base_dirs.push(exe_dir);
+    }
+    for base_dir in base_dirs {
+        paths.push(base_dir.join("models").join("onnx"));
+        paths.push(base_dir.join("models"));
+    }
+
+    paths
+}
+
+/// Finds `{model_name}.onnx` in the first of [`model_search_paths`] that has it.
+pub fn find_model_file(model_name: &str) -> Result<PathBuf, SessionError> {
+    let file_name = format!("{}.onnx", model_name);
+    let search_paths = model_search_paths();
+
+    search_paths
+        .iter()
+        .map(|dir| dir.join(&file_name))
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            SessionError::ModelLoadError(format!(
+                "{} not found, searched: {}",
+                file_name,
+                search_paths
+                    .iter()
+                    .map(|dir| dir.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
         })
+}
+
+/// Batch size fixed by the model's first input, or `None` if it is dynamic.
+pub(crate) fn static_batch_size(session: &Session) -> Option<usize> {
+    session
+        .inputs
+        .first()
+        .and_then(|input| input.input_type.tensor_dimensions())
+        .and_then(|dims| dims.first())
+        .filter(|&&batch| batch > 0)
+        .map(|&batch| batch as usize)
+}
+
+/// Stacks single-image `[1, ...]` tensors along the batch axis. Models with a
+/// static batch size larger than the number of images get the last image
+/// repeated, and the extra outputs are dropped by the caller.
+pub(crate) fn stack_batch(
+    tensors: &[Array4<f32>],
+    batch_size: usize,
+) -> Result<Array4<f32>, ndarray::ShapeError> {
+    let mut views = tensors
+        .iter()
+        .map(|tensor| tensor.view())
+        .collect::<Vec<_>>();
+    if let Some(last) = views.last().copied() {
+        views.resize(batch_size.max(views.len()), last);
     }
+
+    ndarray::concatenate(Axis(0), &views)
+}
+
+/// Splits `tensors` into runs of at most `chunk_size` tensors that have the
+/// same shape and can be stacked into one batch.
+pub(crate) fn batch_ranges(tensors: &[Array4<f32>], chunk_size: usize) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (index, tensor) in tensors.iter().enumerate() {
+        match ranges.last_mut() {
+            Some(range)
+                if range.len() < chunk_size && tensors[range.start].shape() == tensor.shape() =>
+            {
+                range.end = index + 1;
+            }
+            _ => ranges.push(index..index + 1),
+        }
+    }
+    ranges
+}
+
+/// Splits the output of a `batch_size` input into views for the first `count`
+/// images. An output without a batch axis is accepted for a single image.
+pub(crate) fn split_batch(
+    output: ArrayViewD<'_, f32>,
+    batch_size: usize,
+    count: usize,
+) -> Result<Vec<ArrayViewD<'_, f32>>, SessionError> {
+    if output.ndim() > 2 && output.shape()[0] == batch_size {
+        Ok((0..count)
+            .map(|i| output.clone().index_axis_move(Axis(0), i))
+            .collect())
+    } else if count == 1 {
+        Ok(vec![output])
+    } else {
+        Err(SessionError::NoOutput)
+    }
+}
+
+/// Per-request matting options. Fields left as `None` fall back to the
+/// model's defaults.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MattingOptions {
+    pub resize_mode: Option<ResizeMode>,
+    /// Re-run uncertain regions of large images at full resolution, see
+    /// [`refine_tiled`].
+    pub tiled: bool,
+    /// Input sizes to run the model at, fusing the masks. Empty runs once at
+    /// the model's input size.
+    pub scales: Vec<u32>,
+    /// Input size of a single pass, set for each of `scales`. See
+    /// [`Letterbox::new`](super::letterbox::Letterbox::new).
+    pub scale: Option<u32>,
+    /// Also run on augmented copies of the image and merge the masks with
+    /// `tta_fusion`.
+    pub tta: Option<TtaMode>,
+    pub tta_fusion: FusionMode,
+    /// Scale the mask back to the original size with an edge-aware
+    /// upsampler instead of the model's resize filter.
+    pub upsampler: Option<Upsampler>,
 }
 
 pub trait BaseSessionTrait {
@@ -138,8 +542,46 @@ pub trait BaseSessionTrait {
     fn run(
         &self,
         original_image: DynamicImage,
+        options: &MattingOptions,
     ) -> Result<ndarray::Array3<u8>, Box<dyn std::error::Error>>;
 
+    /// Predicts one mask per image. The default runs the images one by one;
+    /// sessions that can stack their inputs override it to run the model once
+    /// per batch.
+    fn run_batch(
+        &self,
+        images: &[DynamicImage],
+        options: &MattingOptions,
+    ) -> Result<Vec<ndarray::Array3<u8>>, Box<dyn std::error::Error>> {
+        images
+            .iter()
+            .map(|image| self.run(image.clone(), options))
+            .collect()
+    }
+
+    /// Predicts one mask per image with [`run_batch`](Self::run_batch), once
+    /// per requested scale and augmentation, then applies the refinements
+    /// requested in `options`.
+    fn predict_batch(
+        &self,
+        images: &[DynamicImage],
+        options: &MattingOptions,
+    ) -> Result<Vec<ndarray::Array3<u8>>, Box<dyn std::error::Error>> {
+        let masks = match options.tta {
+            None => run_scales(self, images, options)?,
+            Some(mode) => run_augmented(self, images, options, mode)?,
+        };
+        if !options.tiled {
+            return Ok(masks);
+        }
+
+        images
+            .iter()
+            .zip(masks)
+            .map(|(image, mask)| refine_tiled(self, image, mask, options))
+            .collect()
+    }
+
     fn post_process(
         &self,
         output: ndarray::Array3<u8>,
@@ -147,6 +589,74 @@ pub trait BaseSessionTrait {
     ) -> Result<ndarray::Array3<u8>, Box<dyn std::error::Error>>;
 
     fn get_model_name(&self) -> String;
+
+    /// Size of the square input the model runs at.
+    fn input_size(&self) -> u32;
+}
+
+/// Runs `images` once per scale in `options.scales` and fuses the masks by
+/// confidence, or once at the model's input size if there are none.
+fn run_scales<S: BaseSessionTrait + ?Sized>(
+    session: &S,
+    images: &[DynamicImage],
+    options: &MattingOptions,
+) -> Result<Vec<ndarray::Array3<u8>>, Box<dyn std::error::Error>> {
+    if options.scales.is_empty() {
+        return session.run_batch(images, options);
+    }
+
+    let passes = options
+        .scales
+        .iter()
+        .map(|&scale| {
+            let pass_options = MattingOptions {
+                scales: Vec::new(),
+                scale: Some(scale),
+                ..options.clone()
+            };
+            session.run_batch(images, &pass_options)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((0..images.len())
+        .map(|index| confidence_weighted_mean(passes.iter().map(|masks| &masks[index])))
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Runs `images` and their augmented copies for `mode`, maps the masks back
+/// onto the original images and merges them with `options.tta_fusion`.
+fn run_augmented<S: BaseSessionTrait + ?Sized>(
+    session: &S,
+    images: &[DynamicImage],
+    options: &MattingOptions,
+    mode: TtaMode,
+) -> Result<Vec<ndarray::Array3<u8>>, Box<dyn std::error::Error>> {
+    let mut passes = Vec::new();
+    for augmentation in Augmentation::for_mode(mode) {
+        let masks = if augmentation == Augmentation::Identity {
+            run_scales(session, images, options)?
+        } else {
+            let augmented = images
+                .iter()
+                .map(|image| augmentation.apply(image))
+                .collect::<Vec<_>>();
+            run_scales(session, &augmented, options)?
+        };
+
+        passes.push(
+            masks
+                .into_iter()
+                .map(|mask| augmentation.invert(mask))
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+    }
+
+    Ok((0..images.len())
+        .map(|index| {
+            let masks = passes.iter().map(|masks| &masks[index]).collect::<Vec<_>>();
+            fuse(options.tta_fusion, &masks)
+        })
+        .collect::<Result<Vec<_>, _>>()?)
 }
 
 #[derive(thiserror::Error, Debug, Clone, PartialEq)]
@@ -158,8 +668,12 @@ pub enum SessionError {
     NoOutput,
     #[error("Image processing error")]
     ImageProcessingError,
-    #[error("Model loading error")]
-    ModelLoadError,
+    #[error("Model loading error: {0}")]
+    ModelLoadError(String),
     #[error("Model not implemented")]
     NotImplemented,
+    #[error("Unknown model: {0}")]
+    UnknownModel(String),
+    #[error("Model disabled: {0}")]
+    ModelDisabled(String),
 }
@@ -0,0 +1,237 @@
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use image::DynamicImage;
+use ndarray::Array3;
+use once_cell::sync::Lazy;
+use rocket::serde::Deserialize;
+
+use super::base::{MattingOptions, SessionError, SessionOptions};
+use super::registry::MODEL_REGISTRY;
+use crate::utils::config::{ConfigCell, ConfigSection};
+
+/// `[default.batching]` section of Rocket.toml.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+pub struct BatchConfig {
+    /// Most images run in one model call; `1` disables micro-batching.
+    pub max_batch_size: usize,
+    /// How long the first request of a batch waits for others to join.
+    pub max_wait_ms: u64,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 1,
+            max_wait_ms: 10,
+        }
+    }
+}
+
+impl ConfigSection for BatchConfig {
+    const SECTION: &'static str = "batching";
+
+    fn validate(&self) -> Result<(), String> {
+        if self.max_batch_size == 0 {
+            return Err("max_batch_size must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// `[default.batching]`, validated at ignite.
+pub static BATCH_CONFIG: ConfigCell<BatchConfig> = ConfigCell::new();
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum BatchError {
+    /// The model could not be found or loaded.
+    #[error(transparent)]
+    Session(#[from] SessionError),
+    #[error("Inference failed: {0}")]
+    Run(String),
+}
+
+type BatchResult = Result<Array3<u8>, BatchError>;
+
+#[derive(Default)]
+struct QueueState {
+    next_id: u64,
+    pending: VecDeque<(u64, DynamicImage, MattingOptions)>,
+    /// Whether a request is currently gathering a batch.
+    gathering: bool,
+    results: HashMap<u64, BatchResult>,
+}
+
+#[derive(Default)]
+struct BatchQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+/// Gathers matting requests for the same model that arrive close together and
+/// runs them through [`BaseSessionTrait::predict_batch`] in one model call.
+///
+/// Requests call [`MicroBatcher::run`] from the inference pool. The first one
+/// to find nobody gathering waits up to `max_wait_ms` for the batch to fill,
+/// takes up to `max_batch_size` queued images and runs them; the others block
+/// until their mask is ready, or take over gathering for what is left in the
+/// queue. Only requests with the same [`MattingOptions`] share a batch. Every
+/// waiting request holds an inference pool slot, so a batch is never larger
+/// than `[default.inference] max_concurrency`.
+///
+/// [`BaseSessionTrait::predict_batch`]: super::base::BaseSessionTrait::predict_batch
+pub struct MicroBatcher {
+    config: BatchConfig,
+    queues: Mutex<HashMap<String, Arc<BatchQueue>>>,
+}
+
+impl MicroBatcher {
+    pub fn new(config: BatchConfig) -> Self {
+        Self {
+            config,
+            queues: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &BatchConfig {
+        &self.config
+    }
+
+    fn queue(&self, model: &str) -> Result<Arc<BatchQueue>, SessionError> {
+        let entry = MODEL_REGISTRY
+            .entry(model)
+            .ok_or_else(|| SessionError::UnknownModel(model.to_owned()))?;
+
+        let mut queues = self
+            .queues
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        Ok(queues.entry(entry.name.to_lowercase()).or_default().clone())
+    }
+
+    /// Predicts the mask of `image` with `model`, possibly batched together
+    /// with other requests for the same model and options.
+    pub fn run(
+        &self,
+        model: &str,
+        session_options: SessionOptions,
+        image: DynamicImage,
+        options: MattingOptions,
+    ) -> BatchResult {
+        if self.config.max_batch_size <= 1 {
+            return Self::run_batch(model, session_options, vec![image], &options)
+                .pop()
+                .unwrap_or(Err(BatchError::Session(SessionError::NoOutput)));
+        }
+
+        let queue = self.queue(model)?;
+        let mut state = queue
+            .state
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        let id = state.next_id;
+        state.next_id += 1;
+        state.pending.push_back((id, image, options.clone()));
+        queue.changed.notify_all();
+
+        loop {
+            if let Some(result) = state.results.remove(&id) {
+                return result;
+            }
+
+            if state.gathering || !state.pending.iter().any(|(pending, ..)| *pending == id) {
+                state = queue
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(|error| error.into_inner());
+                continue;
+            }
+
+            state.gathering = true;
+            let deadline = Instant::now() + Duration::from_millis(self.config.max_wait_ms);
+            while state
+                .pending
+                .iter()
+                .filter(|(.., pending)| *pending == options)
+                .count()
+                < self.config.max_batch_size
+            {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                state = queue
+                    .changed
+                    .wait_timeout(state, remaining)
+                    .unwrap_or_else(|error| error.into_inner())
+                    .0;
+            }
+
+            let mut ids = Vec::new();
+            let mut images = Vec::new();
+            let mut rest = VecDeque::new();
+            for (pending, image, pending_options) in state.pending.drain(..) {
+                if ids.len() < self.config.max_batch_size && pending_options == options {
+                    ids.push(pending);
+                    images.push(image);
+                } else {
+                    rest.push_back((pending, image, pending_options));
+                }
+            }
+            state.pending = rest;
+            state.gathering = false;
+            queue.changed.notify_all();
+            drop(state);
+
+            // A panicking model call must still hand every drained request a
+            // result, or the requests waiting for it would block forever.
+            let count = ids.len();
+            let results = panic::catch_unwind(AssertUnwindSafe(|| {
+                Self::run_batch(model, session_options.clone(), images, &options)
+            }))
+            .unwrap_or_else(|_| {
+                log::error!("Model {} panicked while running a batch", model);
+                vec![Err(BatchError::Run("Inference panicked".to_owned())); count]
+            });
+
+            state = queue
+                .state
+                .lock()
+                .unwrap_or_else(|error| error.into_inner());
+            state.results.extend(ids.into_iter().zip(results));
+            queue.changed.notify_all();
+        }
+    }
+
+    /// Runs `images` in one call on a session checked out from the registry,
+    /// returning one result per image.
+    fn run_batch(
+        model: &str,
+        session_options: SessionOptions,
+        images: Vec<DynamicImage>,
+        options: &MattingOptions,
+    ) -> Vec<BatchResult> {
+        let count = images.len();
+        let masks = MODEL_REGISTRY
+            .get(model, session_options)
+            .map_err(BatchError::from)
+            .and_then(|session| {
+                session.predict_batch(&images, options).map_err(|error| {
+                    log::error!("Error running session: {:?}", error);
+                    BatchError::Run(error.to_string())
+                })
+            });
+
+        match masks {
+            Ok(masks) if masks.len() == count => masks.into_iter().map(Ok).collect(),
+            Ok(_) => vec![Err(BatchError::Session(SessionError::NoOutput)); count],
+            Err(error) => vec![Err(error); count],
+        }
+    }
+}
+
+pub static MICRO_BATCHER: Lazy<MicroBatcher> =
+    Lazy::new(|| MicroBatcher::new(BATCH_CONFIG.get().clone()));
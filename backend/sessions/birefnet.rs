@@ -1,63 +1,128 @@
-use image::{imageops, DynamicImage, ImageBuffer};
-use ndarray::{Array3, Axis};
+use image::{imageops, DynamicImage, GenericImageView, ImageBuffer};
+use ndarray::{Array3, Array4, ArrayViewD, Axis};
 use ort::inputs;
 
-use super::base::{BaseSession, BaseSessionTrait, SessionError, SessionOptions};
+use crate::utils::image_helper::tensor_f32_to_u8;
+
+use super::base::{
+    batch_ranges, split_batch, stack_batch, static_batch_size, BaseSession, BaseSessionTrait,
+    MattingOptions, SessionError, SessionOptions,
+};
+use super::descriptor::{Activation, ResizeMode};
+use super::letterbox::Letterbox;
+use super::tensor::ElementType;
+use super::upsample::Upsampler;
+
+/// Input and output element types BiRefNet exports come in: the original FP32
+/// export, the half-size FP16 export, and `uint8` quantized ones.
+const SUPPORTED_TYPES: [ElementType; 3] = [
+    ElementType::Float32,
+    ElementType::Float16,
+    ElementType::Uint8,
+];
 
 pub struct BirefnetSession {
     pub(crate) input_size: u32,
     pub(crate) mean: [f32; 3],
     pub(crate) std: [f32; 3],
+    pub(crate) input_name: String,
+    /// Whether the model accepts any input height and width.
+    pub(crate) dynamic_size: bool,
+    pub(crate) input_type: ElementType,
+    pub(crate) output_type: ElementType,
+    /// Transform of the raw output, [`Activation::None`] unless the
+    /// `activation` session setting of `birefnet` says otherwise.
+    pub(crate) activation: Activation,
+    /// How images are fitted into the input when the request does not say,
+    /// [`ResizeMode::Stretch`] unless the `resize_mode` session setting of
+    /// `birefnet` says otherwise.
+    pub(crate) resize_mode: ResizeMode,
     pub model_name: String,
     pub(crate) base_session: Option<BaseSession>,
 }
 
 impl BirefnetSession {
-    pub fn new(debug: bool, session_options: SessionOptions) -> Self {
+    pub fn new(
+        debug: bool,
+        session_options: SessionOptions,
+        activation: Activation,
+        resize_mode: ResizeMode,
+    ) -> Result<Self, SessionError> {
         let model_name = "BiRefNet-general-bb_swin_v1_tiny-epoch_232";
-        let base_session = BaseSession::new(debug, session_options, model_name);
+        let base_session = BaseSession::new(debug, session_options, model_name)?;
+        let input_size = 1024;
 
-        if let Err(error) = base_session {
-            panic!("Failed to create session: {}", error);
-        }
+        let mut input_name = String::new();
+        let mut dynamic_size = false;
+        let mut input_type = ElementType::Float32;
+        let mut output_type = ElementType::Float32;
+        base_session.validate(|metadata| {
+            let input = metadata.input(None)?;
+            input.check_dims(
+                "input",
+                &[None, Some(3), Some(input_size), Some(input_size)],
+            )?;
+            input_name = input.name.clone();
+            dynamic_size = input.dim(2).is_none() || input.dim(3).is_none();
+            input_type = ElementType::of(input, "input", &SUPPORTED_TYPES)?;
 
-        Self {
-            input_size: 1024,
+            let output = metadata.output(0)?;
+            output.check_dims("output", &[None, Some(1), None, None])?;
+            output_type = ElementType::of(output, "output", &SUPPORTED_TYPES)?;
+            Ok(())
+        })?;
+
+        Ok(Self {
+            input_size: input_size as u32,
             mean: [0.485, 0.456, 0.406],
             std: [0.229, 0.224, 0.225],
+            input_name,
+            dynamic_size,
+            input_type,
+            output_type,
+            activation,
+            resize_mode,
             model_name: model_name.to_string(),
-            base_session: base_session.ok(),
-        }
+            base_session: Some(base_session),
+        })
     }
 }
 
-impl BaseSessionTrait for BirefnetSession {
-    fn get_model_name(&self) -> String {
-        self.model_name.clone()
-    }
-
-    fn run(
+impl BirefnetSession {
+    /// Resizes and normalizes one image into a `[1, 3, height, width]` tensor.
+    /// Images are fitted to the input size with the requested resize mode, or
+    /// the session's own.
+    fn pre_process(
         &self,
-        original_image: DynamicImage,
-    ) -> Result<ndarray::Array3<u8>, Box<dyn std::error::Error>> {
-        let mask_size = self.input_size;
-        let original_width = original_image.width();
-        let original_height = original_image.height();
-        log::info!(
-            "Original image size: {}x{}",
-            original_width,
-            original_height
+        image: &DynamicImage,
+        options: &MattingOptions,
+    ) -> Result<(Array4<f32>, Letterbox), SessionError> {
+        let letterbox = Letterbox::new(
+            options.resize_mode.unwrap_or(self.resize_mode),
+            image.width(),
+            image.height(),
+            self.input_size,
+            options.scale,
+            self.dynamic_size,
         );
-
-        let resized_img = original_image.resize_exact(mask_size, mask_size, imageops::Lanczos3);
+        let resized_img = letterbox.apply(image, imageops::Lanczos3);
         let image_buffer_array = Array3::<u8>::from_shape_vec(
-            (mask_size as usize, mask_size as usize, 3_usize),
-            resized_img.to_rgb8().to_vec(),
+            (letterbox.height as usize, letterbox.width as usize, 3_usize),
+            resized_img.to_vec(),
         );
 
         if let Err(error) = image_buffer_array {
             log::error!("Error: {:?}", error);
-            return Err(Box::new(SessionError::ImageProcessingError));
+            return Err(SessionError::ImageProcessingError);
+        }
+
+        // Models with `uint8` inputs normalize the raw pixels themselves.
+        if self.input_type == ElementType::Uint8 {
+            let input_array = image_buffer_array.unwrap().mapv(|x| x as f32);
+            return Ok((
+                input_array.permuted_axes([2, 0, 1]).insert_axis(Axis(0)),
+                letterbox,
+            ));
         }
 
         let mut input_array = image_buffer_array.unwrap().mapv(|x| x as f32 / 255.0);
@@ -67,29 +132,37 @@ impl BaseSessionTrait for BirefnetSession {
             });
         }
 
-        let input_tensor = input_array.permuted_axes([2, 0, 1]).insert_axis(Axis(0));
-
-        let model = self.get_session();
+        Ok((
+            input_array.permuted_axes([2, 0, 1]).insert_axis(Axis(0)),
+            letterbox,
+        ))
+    }
 
-        if model.is_none() {
-            return Err(Box::new(SessionError::ModelLoadError));
+    /// Crops the letterbox padding off the mask of one image and scales it back
+    /// to the size of `original_image`, with `upsampler` if given.
+    fn mask_to_original(
+        &self,
+        output: ArrayViewD<'_, f32>,
+        letterbox: &Letterbox,
+        original_image: &DynamicImage,
+        upsampler: Option<Upsampler>,
+    ) -> Result<Array3<u8>, Box<dyn std::error::Error>> {
+        let (original_width, original_height) = original_image.dimensions();
+        // The output was checked to be `[batch, 1, height, width]` at load time.
+        let dims = output.shape();
+        let (mask_height, mask_width) = match dims.len() {
+            len if len >= 2 => (dims[len - 2], dims[len - 1]),
+            _ => return Err(Box::new(SessionError::ImageProcessingError)),
+        };
+        let alpha_mask_raw = output.to_shape((mask_height, mask_width))?.to_owned();
+        let alpha_mask_raw = self.activation.apply(letterbox.unpad(alpha_mask_raw));
+        if let Some(upsampler) = upsampler {
+            let alpha_mask = upsampler.upsample(&alpha_mask_raw, original_image);
+            return Ok(tensor_f32_to_u8(alpha_mask.insert_axis(Axis(2))));
         }
+        let (mask_height, mask_width) = alpha_mask_raw.dim();
 
-        let model = model.unwrap();
-        let ort_inputs = inputs![input_tensor]?;
-
-        let ort_outputs = model.run(ort_inputs)?;
-
-        let output_tensor = ort_outputs[0].try_extract_tensor::<f32>()?;
-
-        let alpha_mask_raw = output_tensor
-            .to_shape((1, 1, mask_size as usize, mask_size as usize))?
-            .remove_axis(Axis(0));
-
-        let alpha_mask = alpha_mask_raw.permuted_axes([1, 2, 0]);
-
-        let alpha_mask = alpha_mask.mapv(|x| (x * 255.0).round() as u8);
-        let (mask_width, mask_height, _) = alpha_mask.dim();
+        let alpha_mask = tensor_f32_to_u8(alpha_mask_raw.insert_axis(Axis(2)));
 
         let alpha_image = DynamicImage::ImageLuma8(
             ImageBuffer::from_vec(
@@ -108,6 +181,83 @@ impl BaseSessionTrait for BirefnetSession {
 
         Ok(output_array)
     }
+}
+
+impl BaseSessionTrait for BirefnetSession {
+    fn get_model_name(&self) -> String {
+        self.model_name.clone()
+    }
+
+    fn input_size(&self) -> u32 {
+        self.input_size
+    }
+
+    fn run(
+        &self,
+        original_image: DynamicImage,
+        options: &MattingOptions,
+    ) -> Result<ndarray::Array3<u8>, Box<dyn std::error::Error>> {
+        log::info!(
+            "Original image size: {}x{}",
+            original_image.width(),
+            original_image.height()
+        );
+
+        self.run_batch(std::slice::from_ref(&original_image), options)?
+            .pop()
+            .ok_or_else(|| Box::new(SessionError::NoOutput).into())
+    }
+
+    fn run_batch(
+        &self,
+        images: &[DynamicImage],
+        options: &MattingOptions,
+    ) -> Result<Vec<ndarray::Array3<u8>>, Box<dyn std::error::Error>> {
+        let model = self.get_session();
+
+        if model.is_none() {
+            return Err(Box::new(SessionError::PredictError));
+        }
+
+        let model = model.unwrap();
+        let static_batch = static_batch_size(model);
+        let chunk_size = static_batch.unwrap_or(images.len()).max(1);
+        let mut masks = Vec::with_capacity(images.len());
+
+        let (input_tensors, letterboxes): (Vec<_>, Vec<_>) = images
+            .iter()
+            .map(|image| self.pre_process(image, options))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .unzip();
+
+        for range in batch_ranges(&input_tensors, chunk_size) {
+            let chunk = &images[range.clone()];
+            let input_tensor = stack_batch(
+                &input_tensors[range.clone()],
+                static_batch.unwrap_or(range.len()),
+            )?;
+            let batch_size = input_tensor.len_of(Axis(0));
+
+            let ort_inputs = inputs![
+                self.input_name.as_str() => self.input_type.to_value(input_tensor)?
+            ]?;
+
+            let ort_outputs = model.run(ort_inputs)?;
+
+            let output_tensor = self.output_type.extract(&ort_outputs[0])?;
+
+            for ((image, letterbox), output) in chunk
+                .iter()
+                .zip(&letterboxes[range])
+                .zip(split_batch(output_tensor.view(), batch_size, chunk.len())?)
+            {
+                masks.push(self.mask_to_original(output, letterbox, image, options.upsampler)?);
+            }
+        }
+
+        Ok(masks)
+    }
 
     fn get_session(&self) -> Option<&ort::Session> {
         self.base_session
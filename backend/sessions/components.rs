@@ -0,0 +1,315 @@
+use ndarray::Array2;
+
+/// Which connected components of the mask to keep and which holes to fill.
+/// Components are the 8-connected regions above the threshold; holes are the
+/// 4-connected regions at or below it that do not touch the image border.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ComponentFilter {
+    /// Keep only the largest component.
+    pub keep_largest: bool,
+    /// Drop components smaller than this many pixels.
+    pub min_area: Option<u32>,
+    /// Drop components smaller than this fraction of the largest one.
+    pub min_relative_area: Option<f32>,
+    /// Fill holes of at most this many pixels.
+    pub fill_holes: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Connectivity {
+    Four,
+    Eight,
+}
+
+/// A connected region found by [`label`].
+#[derive(Debug, Clone, Copy, Default)]
+struct Component {
+    area: usize,
+    touches_border: bool,
+}
+
+impl ComponentFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Removes the unwanted components of `mask` and fills its small holes.
+    ///
+    /// Pixels above `threshold` are the cores of the components. Soft pixels
+    /// around a kept core keep their value; those only connected to removed
+    /// cores are cleared too, so no faint halo is left where a blob was.
+    pub fn apply(&self, mask: &mut Array2<u8>, threshold: u8) {
+        if self.is_empty() {
+            return;
+        }
+
+        let (height, width) = mask.dim();
+        let mut values = mask.iter().copied().collect::<Vec<_>>();
+
+        if self.keep_largest || self.min_area.is_some() || self.min_relative_area.is_some() {
+            let cores = values
+                .iter()
+                .map(|&value| value > threshold)
+                .collect::<Vec<_>>();
+            let (labels, components) = label(&cores, width, height, Connectivity::Eight);
+            let keep = self.kept_components(&components);
+
+            // Soft pixels belong to the kept part of the mask if their region of
+            // non-zero alpha contains a kept core.
+            let support = values.iter().map(|&value| value > 0).collect::<Vec<_>>();
+            let (support_labels, support_components) =
+                label(&support, width, height, Connectivity::Eight);
+            let mut kept_support = vec![false; support_components.len() + 1];
+            for (&label, &support_label) in labels.iter().zip(&support_labels) {
+                if label != 0 && keep[label as usize] {
+                    kept_support[support_label as usize] = true;
+                }
+            }
+
+            for (index, value) in values.iter_mut().enumerate() {
+                let kept = match labels[index] {
+                    0 => kept_support[support_labels[index] as usize],
+                    label => keep[label as usize],
+                };
+                if !kept {
+                    *value = 0;
+                }
+            }
+        }
+
+        if let Some(max_hole) = self.fill_holes {
+            let background = values
+                .iter()
+                .map(|&value| value <= threshold)
+                .collect::<Vec<_>>();
+            let (labels, holes) = label(&background, width, height, Connectivity::Four);
+            for (value, &label) in values.iter_mut().zip(&labels) {
+                if label == 0 {
+                    continue;
+                }
+                let hole = holes[label as usize - 1];
+                if !hole.touches_border && hole.area <= max_hole as usize {
+                    *value = 255;
+                }
+            }
+        }
+
+        for (pixel, value) in mask.iter_mut().zip(values) {
+            *pixel = value;
+        }
+    }
+
+    /// Whether to keep each component, indexed by label; label 0 is unused.
+    fn kept_components(&self, components: &[Component]) -> Vec<bool> {
+        let largest = components
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, component)| component.area)
+            .map(|(index, component)| (index, component.area));
+        let Some((largest_index, largest_area)) = largest else {
+            return vec![false];
+        };
+
+        let mut keep = vec![false];
+        keep.extend(components.iter().enumerate().map(|(index, component)| {
+            (!self.keep_largest || index == largest_index)
+                && self
+                    .min_area
+                    .is_none_or(|min_area| component.area >= min_area as usize)
+                && self.min_relative_area.is_none_or(|min_relative_area| {
+                    component.area as f32 >= min_relative_area * largest_area as f32
+                })
+        }));
+        keep
+    }
+}
+
+/// Labels the connected regions of the pixels set in `inside`, a row-major
+/// `width x height` grid. Returns each pixel's label, 0 outside, and the
+/// regions indexed by label - 1.
+fn label(
+    inside: &[bool],
+    width: usize,
+    height: usize,
+    connectivity: Connectivity,
+) -> (Vec<u32>, Vec<Component>) {
+    let mut labels = vec![0_u32; inside.len()];
+    let mut components = Vec::new();
+    let mut stack = Vec::new();
+
+    for start in 0..inside.len() {
+        if !inside[start] || labels[start] != 0 {
+            continue;
+        }
+
+        let label = components.len() as u32 + 1;
+        let mut component = Component::default();
+        labels[start] = label;
+        stack.push(start);
+
+        while let Some(index) = stack.pop() {
+            let (x, y) = (index % width, index / width);
+            component.area += 1;
+            component.touches_border |= x == 0 || y == 0 || x == width - 1 || y == height - 1;
+
+            for dy in -1_isize..=1 {
+                for dx in -1_isize..=1 {
+                    let diagonal = dx != 0 && dy != 0;
+                    if (dx == 0 && dy == 0) || (diagonal && connectivity == Connectivity::Four) {
+                        continue;
+                    }
+                    let (Some(nx), Some(ny)) = (x.checked_add_signed(dx), y.checked_add_signed(dy))
+                    else {
+                        continue;
+                    };
+                    if nx >= width || ny >= height {
+                        continue;
+                    }
+
+                    let neighbour = ny * width + nx;
+                    if inside[neighbour] && labels[neighbour] == 0 {
+                        labels[neighbour] = label;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+        }
+        components.push(component);
+    }
+
+    (labels, components)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a mask from rows of `#` (255), `o` (a soft 60) and `.` (0).
+    fn mask(rows: &[&str]) -> Array2<u8> {
+        let values = rows
+            .iter()
+            .flat_map(|row| row.chars())
+            .map(|pixel| match pixel {
+                '#' => 255,
+                'o' => 60,
+                _ => 0,
+            })
+            .collect();
+        Array2::from_shape_vec((rows.len(), rows[0].len()), values).unwrap()
+    }
+
+    fn inside(mask: &Array2<u8>) -> Vec<bool> {
+        mask.iter().map(|&value| value > 127).collect()
+    }
+
+    #[test]
+    fn label_joins_diagonals_only_with_eight_connectivity() {
+        let mask = mask(&["#...", ".#..", "...#", "...."]);
+        let inside = inside(&mask);
+
+        let (labels, components) = label(&inside, 4, 4, Connectivity::Eight);
+        assert_eq!(components.len(), 2);
+        assert_eq!(components[0].area, 2);
+        assert_eq!(labels[0], labels[5]);
+
+        let (_, components) = label(&inside, 4, 4, Connectivity::Four);
+        assert_eq!(components.len(), 3);
+    }
+
+    #[test]
+    fn label_reports_components_touching_the_border() {
+        let mask = mask(&["#....", ".....", "..#..", ".....", "....."]);
+        let (_, components) = label(&inside(&mask), 5, 5, Connectivity::Eight);
+
+        assert!(components[0].touches_border);
+        assert!(!components[1].touches_border);
+    }
+
+    #[test]
+    fn keep_largest_removes_smaller_components_and_their_halo() {
+        let mut mask = mask(&[
+            "ooooo.....",
+            "o###o.....",
+            "o###o..oo.",
+            "o###o..o#o",
+            "ooooo..oo.",
+        ]);
+        let filter = ComponentFilter {
+            keep_largest: true,
+            ..Default::default()
+        };
+        filter.apply(&mut mask, 127);
+
+        let expected = self::mask(&[
+            "ooooo.....",
+            "o###o.....",
+            "o###o.....",
+            "o###o.....",
+            "ooooo.....",
+        ]);
+        assert_eq!(mask, expected);
+    }
+
+    #[test]
+    fn min_area_keeps_large_enough_components() {
+        let mut mask = mask(&["##...#", "##....", "......", "...##."]);
+        let filter = ComponentFilter {
+            min_area: Some(2),
+            ..Default::default()
+        };
+        filter.apply(&mut mask, 127);
+
+        assert_eq!(mask, self::mask(&["##....", "##....", "......", "...##."]));
+    }
+
+    #[test]
+    fn min_relative_area_compares_with_the_largest_component() {
+        let mut mask = mask(&["###.#", "###..", "###.#", "....#"]);
+        let filter = ComponentFilter {
+            min_relative_area: Some(0.2),
+            ..Default::default()
+        };
+        filter.apply(&mut mask, 127);
+
+        assert_eq!(mask, self::mask(&["###..", "###..", "###.#", "....#"]));
+    }
+
+    #[test]
+    fn fill_holes_skips_large_holes_and_holes_on_the_border() {
+        let mut mask = mask(&["#######..", "#.##..#.#", "####..###", "#########"]);
+        let filter = ComponentFilter {
+            fill_holes: Some(3),
+            ..Default::default()
+        };
+        filter.apply(&mut mask, 127);
+
+        // The one pixel hole is filled, the 2x2 one is too large and the one
+        // open to the top border is not a hole.
+        assert_eq!(
+            mask,
+            self::mask(&["#######..", "####..#.#", "####..###", "#########",])
+        );
+    }
+
+    #[test]
+    fn holes_are_four_connected() {
+        // The diagonal gaps split the background into separate holes.
+        let mut mask = mask(&["#####", "#.###", "##.##", "#####"]);
+        let filter = ComponentFilter {
+            fill_holes: Some(1),
+            ..Default::default()
+        };
+        filter.apply(&mut mask, 127);
+
+        assert!(mask.iter().all(|&value| value == 255));
+    }
+
+    #[test]
+    fn empty_filter_leaves_the_mask_unchanged() {
+        let original = mask(&["#.o", "...", "o.#"]);
+        let mut mask = original.clone();
+        ComponentFilter::default().apply(&mut mask, 127);
+
+        assert_eq!(mask, original);
+    }
+}
@@ -0,0 +1,291 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use image::imageops::FilterType;
+use ndarray::Array2;
+use rocket::figment::providers::{Format, Toml};
+use rocket::figment::Figment;
+use rocket::serde::{Deserialize, Serialize};
+
+use super::base::{find_model_file, model_search_paths, SessionError};
+
+/// Memory layout of the model's image input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum TensorLayout {
+    Nchw,
+    Nhwc,
+}
+
+/// How pixel values are scaled before `mean` and `std` are applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Normalization {
+    /// Pixels are divided by 255 first, so `mean`/`std` are in [0, 1].
+    Unit,
+    /// Pixels stay in [0, 255], so `mean`/`std` are in that range too.
+    Raw,
+}
+
+/// Transform applied to the raw model output before it is turned into a mask,
+/// once the letterbox padding has been cut off so that it does not skew
+/// [`Activation::MinMax`]. The mask is always clamped to [0, 1] when it is quantized, whatever the
+/// transform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Activation {
+    /// Use the output as it is, for models that end in a sigmoid.
+    #[serde(alias = "identity")]
+    None,
+    /// For exports that output raw logits.
+    Sigmoid,
+    /// Stretch the output to [0, 1], as rembg does for U2Net.
+    MinMax,
+    /// Clamp to [0, 1] before the mask is resized, so overshooting values
+    /// do not bleed into the edges.
+    Clamp,
+}
+
+impl Activation {
+    pub fn apply(self, mask: Array2<f32>) -> Array2<f32> {
+        match self {
+            Activation::None => mask,
+            Activation::Sigmoid => mask.mapv(|x| 1.0 / (1.0 + (-x).exp())),
+            Activation::MinMax => {
+                let min = mask.fold(f32::INFINITY, |acc, &x| acc.min(x));
+                let max = mask.fold(f32::NEG_INFINITY, |acc, &x| acc.max(x));
+                let range = (max - min).max(f32::EPSILON);
+                mask.mapv(|x| (x - min) / range)
+            }
+            Activation::Clamp => mask.mapv(|x| x.clamp(0.0, 1.0)),
+        }
+    }
+}
+
+/// Filter used to resize the image to the model input and the mask back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum ResizeStrategy {
+    Nearest,
+    Bilinear,
+    Lanczos3,
+}
+
+impl ResizeStrategy {
+    pub fn filter_type(&self) -> FilterType {
+        match self {
+            ResizeStrategy::Nearest => FilterType::Nearest,
+            ResizeStrategy::Bilinear => FilterType::Triangle,
+            ResizeStrategy::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// How the image is fitted into the model input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum ResizeMode {
+    /// Resize to the input size, ignoring the aspect ratio.
+    #[default]
+    Stretch,
+    /// Keep the aspect ratio and pad to the input size. See
+    /// [`Letterbox`](super::letterbox::Letterbox).
+    Letterbox,
+}
+
+impl FromStr for ResizeMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "stretch" => Ok(ResizeMode::Stretch),
+            "letterbox" => Ok(ResizeMode::Letterbox),
+            _ => Err(format!(
+                "Unknown resize mode {}, expected one of: stretch, letterbox",
+                value
+            )),
+        }
+    }
+}
+
+/// Everything needed to run a single-input, single-mask matting model.
+///
+/// Descriptors are read from `{name}.toml` files next to the `.onnx` files in
+/// any of the model search paths, for example:
+///
+/// ```toml
+/// file = "silueta.onnx"
+/// input_size = 320
+/// normalization = "raw"
+/// mean = [123.675, 116.28, 103.53]
+/// std = [58.395, 57.12, 57.375]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct ModelDescriptor {
+    /// Registry name, defaults to the descriptor's file stem.
+    pub name: String,
+    /// Model file, relative to the descriptor's directory. Defaults to `{name}.onnx`.
+    pub file: Option<PathBuf>,
+    /// Name of the image input, defaults to the model's first input.
+    pub input_name: Option<String>,
+    pub layout: TensorLayout,
+    pub input_size: u32,
+    pub normalization: Normalization,
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+    pub output_index: usize,
+    pub activation: Activation,
+    /// Filter used to resize the mask back, and the image to the input
+    /// unless `input_resize` is set.
+    pub resize: ResizeStrategy,
+    pub input_resize: Option<ResizeStrategy>,
+    /// Default for requests that do not pick a resize mode.
+    pub resize_mode: ResizeMode,
+    #[serde(skip)]
+    pub base_dir: Option<PathBuf>,
+}
+
+impl Default for ModelDescriptor {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            file: None,
+            input_name: None,
+            layout: TensorLayout::Nchw,
+            input_size: 320,
+            normalization: Normalization::Unit,
+            mean: [0.485, 0.456, 0.406],
+            std: [0.229, 0.224, 0.225],
+            output_index: 0,
+            activation: Activation::None,
+            resize: ResizeStrategy::Lanczos3,
+            input_resize: None,
+            resize_mode: ResizeMode::Stretch,
+            base_dir: None,
+        }
+    }
+}
+
+impl ModelDescriptor {
+    pub fn u2net() -> Self {
+        Self {
+            name: "u2net".to_owned(),
+            input_size: 320,
+            normalization: Normalization::Raw,
+            mean: [123.675, 116.28, 103.53],
+            std: [58.395, 57.120, 57.375],
+            activation: Activation::MinMax,
+            resize: ResizeStrategy::Bilinear,
+            input_resize: Some(ResizeStrategy::Lanczos3),
+            ..Self::default()
+        }
+    }
+
+    pub fn isnet() -> Self {
+        Self {
+            name: "isnet".to_owned(),
+            file: Some(PathBuf::from("isnet-general-use.onnx")),
+            input_size: 1024,
+            normalization: Normalization::Raw,
+            mean: [128.0, 128.0, 128.0],
+            std: [256.0, 256.0, 256.0],
+            activation: Activation::MinMax,
+            resize: ResizeStrategy::Bilinear,
+            input_resize: Some(ResizeStrategy::Lanczos3),
+            ..Self::default()
+        }
+    }
+
+    /// Filter used to resize the image to the model input.
+    pub fn input_filter(&self) -> FilterType {
+        self.input_resize.unwrap_or(self.resize).filter_type()
+    }
+
+    /// Descriptors that are available without any descriptor file.
+    pub fn builtin() -> Vec<Self> {
+        vec![Self::u2net(), Self::isnet()]
+    }
+
+    /// Reads a descriptor from a TOML file.
+    pub fn from_file(path: &Path) -> Result<Self, SessionError> {
+        let mut descriptor: Self = Figment::from(Toml::file(path)).extract().map_err(|error| {
+            SessionError::ModelLoadError(format!(
+                "Invalid model descriptor {}: {}",
+                path.display(),
+                error
+            ))
+        })?;
+
+        if descriptor.name.is_empty() {
+            descriptor.name = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+        }
+        descriptor.base_dir = path.parent().map(PathBuf::from);
+
+        Ok(descriptor)
+    }
+
+    /// Scans the model search paths for `*.toml` descriptors. Files that fail
+    /// to parse are logged and skipped.
+    pub fn discover() -> Vec<Self> {
+        let mut descriptors = Vec::new();
+
+        for dir in model_search_paths() {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+
+            let mut paths = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+                .collect::<Vec<_>>();
+            paths.sort();
+
+            for path in paths {
+                match Self::from_file(&path) {
+                    Ok(descriptor) => {
+                        log::info!(
+                            "Found model descriptor {} at {}",
+                            descriptor.name,
+                            path.display()
+                        );
+                        descriptors.push(descriptor);
+                    }
+                    Err(error) => log::warn!("{}", error),
+                }
+            }
+        }
+
+        descriptors
+    }
+
+    /// Path of the `.onnx` file this descriptor points to.
+    pub fn model_path(&self) -> Result<PathBuf, SessionError> {
+        let file = self
+            .file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format!("{}.onnx", self.name)));
+
+        match &self.base_dir {
+            Some(base_dir) => {
+                let path = base_dir.join(&file);
+                if path.is_file() {
+                    Ok(path)
+                } else {
+                    Err(SessionError::ModelLoadError(format!(
+                        "{} not found for model descriptor {}",
+                        path.display(),
+                        self.name
+                    )))
+                }
+            }
+            None => {
+                let model_name = file.with_extension("");
+                find_model_file(&model_name.to_string_lossy())
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use image::{DynamicImage, GenericImageView};
+use ndarray::Array3;
+
+use super::base::{MattingOptions, SessionError};
+use super::batcher::{BatchError, MICRO_BATCHER};
+use super::fusion::{fuse, resize_mask, FusionMode};
+use super::registry::MODEL_REGISTRY;
+use super::settings::SESSION_SETTINGS;
+
+/// Prefix of ensemble model names, e.g. `ensemble:birefnet,isnet`.
+pub const ENSEMBLE_PREFIX: &str = "ensemble:";
+
+/// Several matting models run on the same image, their masks merged with
+/// `fusion`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ensemble {
+    pub models: Vec<String>,
+    pub fusion: FusionMode,
+}
+
+impl Ensemble {
+    /// Parses an `ensemble:a,b,...` model name. Returns `None` for the name of
+    /// a single model.
+    pub fn parse(model: &str, fusion: FusionMode) -> Option<Result<Self, String>> {
+        let prefix = model.get(..ENSEMBLE_PREFIX.len())?;
+        if !prefix.eq_ignore_ascii_case(ENSEMBLE_PREFIX) {
+            return None;
+        }
+
+        let models = model[ENSEMBLE_PREFIX.len()..]
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .collect::<Vec<_>>();
+        if models.iter().any(|name| name.is_empty()) {
+            return Some(Err(format!(
+                "Invalid ensemble {}, expected {}model,model,...",
+                model, ENSEMBLE_PREFIX
+            )));
+        }
+
+        Some(Ok(Self { models, fusion }))
+    }
+
+    /// The `ensemble:a,b,...` name of the ensemble.
+    pub fn name(&self) -> String {
+        format!("{}{}", ENSEMBLE_PREFIX, self.models.join(","))
+    }
+
+    /// Predicts the mask of `image` with every model, each through the micro
+    /// batcher like a single-model request, and fuses the masks. Fails before
+    /// running anything if one of the models is unknown.
+    ///
+    /// Errors come with the name of the model that failed, or of the whole
+    /// ensemble if fusing the masks did.
+    pub fn run(
+        &self,
+        image: &DynamicImage,
+        options: &MattingOptions,
+    ) -> Result<Array3<u8>, (String, BatchError)> {
+        if let Some(model) = self
+            .models
+            .iter()
+            .find(|model| MODEL_REGISTRY.entry(model).is_none())
+        {
+            return Err((
+                model.clone(),
+                SessionError::UnknownModel(model.clone()).into(),
+            ));
+        }
+
+        let (width, height) = image.dimensions();
+        let masks = self
+            .models
+            .iter()
+            .map(|model| {
+                let run = || -> Result<Array3<u8>, BatchError> {
+                    let session_options = SESSION_SETTINGS.get().session_options(model)?;
+                    let mask = MICRO_BATCHER.run(
+                        model,
+                        session_options,
+                        image.clone(),
+                        options.clone(),
+                    )?;
+                    Ok(resize_mask(mask, width, height)?)
+                };
+                run().map_err(|error| (model.clone(), error))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        log::info!(
+            "Fusing masks of {} with {:?}",
+            self.models.join(", "),
+            self.fusion
+        );
+        fuse(self.fusion, &masks.iter().collect::<Vec<_>>())
+            .map_err(|error| (self.name(), error.into()))
+    }
+}
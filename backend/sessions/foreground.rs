@@ -0,0 +1,262 @@
+use ndarray::{Array3, Axis};
+
+use super::base::SessionError;
+
+/// Smoothness weight between neighbouring pixels where alpha is flat.
+const REGULARIZATION: f32 = 1e-5;
+/// Extra smoothness weight per unit of alpha difference between neighbours.
+const GRADIENT_WEIGHT: f32 = 1.0;
+/// Levels no larger than this get `SMALL_ITERATIONS`, the rest
+/// `BIG_ITERATIONS`, as the coarse levels are cheap and set the starting point
+/// of every finer one.
+const SMALL_SIZE: usize = 32;
+const SMALL_ITERATIONS: usize = 10;
+const BIG_ITERATIONS: usize = 2;
+
+/// Replaces the color of the semi-transparent pixels of the `H x W x 4` RGBA
+/// cutout `output` with an estimate of the foreground color alone.
+///
+/// The color of an edge pixel is a mix `alpha * F + (1 - alpha) * B` of the
+/// foreground and the old background, so copying it unchanged leaves a halo
+/// of the old background around the cutout. Following Germer et al.,
+/// "Fast Multi-Level Foreground Estimation", `F` and `B` are solved for on a
+/// pyramid from 1x1 up to the full resolution, each level starting from the
+/// previous one upsampled and refined with a few Gauss-Seidel sweeps.
+pub fn decontaminate(mut output: Array3<u8>) -> Result<Array3<u8>, SessionError> {
+    let (height, width, channels) = output.dim();
+    if channels != 4 {
+        log::error!("Expected an RGBA image, got {} channels", channels);
+        return Err(SessionError::ImageProcessingError);
+    }
+    let is_semi_transparent = |alpha: u8| alpha > 0 && alpha < 255;
+    let alpha = output.index_axis(Axis(2), 3);
+    if !alpha.iter().any(|&alpha| is_semi_transparent(alpha)) {
+        return Ok(output);
+    }
+
+    let alpha = alpha
+        .iter()
+        .map(|&alpha| alpha as f32 / 255.0)
+        .collect::<Vec<_>>();
+    let image = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (y, x)))
+        .map(|(y, x)| [0, 1, 2].map(|channel| output[[y, x, channel]] as f32 / 255.0))
+        .collect::<Vec<_>>();
+    let foreground = estimate_foreground(&image, &alpha, width, height);
+
+    for y in 0..height {
+        for x in 0..width {
+            if is_semi_transparent(output[[y, x, 3]]) {
+                for channel in 0..3 {
+                    output[[y, x, channel]] = (foreground[y * width + x][channel] * 255.0)
+                        .round()
+                        .clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// Multi-level estimate of the foreground colors of a `width x height`
+/// `image` with the given `alpha`, both row-major and in 0-1.
+fn estimate_foreground(
+    image: &[[f32; 3]],
+    alpha: &[f32],
+    width: usize,
+    height: usize,
+) -> Vec<[f32; 3]> {
+    let count = image.len().max(1) as f32;
+    let mean = image.iter().fold([0.0_f32; 3], |sum, color| {
+        [0, 1, 2].map(|channel| sum[channel] + color[channel] / count)
+    });
+
+    let levels = (width.max(height) as f32).log2().ceil().max(1.0) as u32;
+    let mut foreground = vec![mean];
+    let mut background = vec![mean];
+    let (mut previous_width, mut previous_height) = (1, 1);
+
+    for level in 0..=levels {
+        let exponent = level as f32 / levels as f32;
+        let level_width = ((width as f32).powf(exponent).round() as usize).clamp(1, width);
+        let level_height = ((height as f32).powf(exponent).round() as usize).clamp(1, height);
+
+        let level_image = resize_nearest(image, width, height, level_width, level_height);
+        let level_alpha = resize_nearest(alpha, width, height, level_width, level_height);
+        foreground = resize_nearest(
+            &foreground,
+            previous_width,
+            previous_height,
+            level_width,
+            level_height,
+        );
+        background = resize_nearest(
+            &background,
+            previous_width,
+            previous_height,
+            level_width,
+            level_height,
+        );
+
+        let iterations = if level_width <= SMALL_SIZE && level_height <= SMALL_SIZE {
+            SMALL_ITERATIONS
+        } else {
+            BIG_ITERATIONS
+        };
+        for _ in 0..iterations {
+            update_level(
+                &level_image,
+                &level_alpha,
+                &mut foreground,
+                &mut background,
+                level_width,
+                level_height,
+            );
+        }
+
+        previous_width = level_width;
+        previous_height = level_height;
+    }
+
+    foreground
+}
+
+/// One Gauss-Seidel sweep over a level: solves the 2x2 system for `F` and `B`
+/// of every pixel, given its color, alpha and the current values of its four
+/// neighbours, weighted more where alpha changes.
+fn update_level(
+    image: &[[f32; 3]],
+    alpha: &[f32],
+    foreground: &mut [[f32; 3]],
+    background: &mut [[f32; 3]],
+    width: usize,
+    height: usize,
+) {
+    for y in 0..height {
+        for x in 0..width {
+            let index = y * width + x;
+            let a0 = alpha[index];
+            let a1 = 1.0 - a0;
+
+            let mut a00 = a0 * a0;
+            let a01 = a0 * a1;
+            let mut a11 = a1 * a1;
+            let mut b0 = image[index].map(|channel| a0 * channel);
+            let mut b1 = image[index].map(|channel| a1 * channel);
+
+            let neighbours = [
+                y * width + x.saturating_sub(1),
+                y * width + (x + 1).min(width - 1),
+                y.saturating_sub(1) * width + x,
+                (y + 1).min(height - 1) * width + x,
+            ];
+            for neighbour in neighbours {
+                let weight = REGULARIZATION + GRADIENT_WEIGHT * (a0 - alpha[neighbour]).abs();
+                a00 += weight;
+                a11 += weight;
+                for channel in 0..3 {
+                    b0[channel] += weight * foreground[neighbour][channel];
+                    b1[channel] += weight * background[neighbour][channel];
+                }
+            }
+
+            let inverse = 1.0 / (a00 * a11 - a01 * a01);
+            for channel in 0..3 {
+                foreground[index][channel] =
+                    (inverse * (a11 * b0[channel] - a01 * b1[channel])).clamp(0.0, 1.0);
+                background[index][channel] =
+                    (inverse * (a00 * b1[channel] - a01 * b0[channel])).clamp(0.0, 1.0);
+            }
+        }
+    }
+}
+
+fn resize_nearest<T: Copy>(
+    values: &[T],
+    width: usize,
+    height: usize,
+    new_width: usize,
+    new_height: usize,
+) -> Vec<T> {
+    (0..new_height)
+        .flat_map(|y| {
+            let source_y = (y * height / new_height).min(height - 1);
+            (0..new_width).map(move |x| {
+                let source_x = (x * width / new_width).min(width - 1);
+                values[source_y * width + source_x]
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FOREGROUND: [f32; 3] = [0.9, 0.2, 0.1];
+    const BACKGROUND: [f32; 3] = [0.1, 0.8, 0.6];
+
+    /// A 32 x 8 RGBA cutout of a flat foreground over a flat background, with
+    /// alpha ramping from 0 on the left to 1 on the right, and the colors
+    /// composited as a camera would have seen them.
+    fn composite() -> Array3<u8> {
+        let (height, width) = (8, 32);
+        Array3::from_shape_fn((height, width, 4), |(_, x, channel)| {
+            let alpha = x as f32 / (width - 1) as f32;
+            let value = if channel == 3 {
+                alpha
+            } else {
+                alpha * FOREGROUND[channel] + (1.0 - alpha) * BACKGROUND[channel]
+            };
+            (value * 255.0).round() as u8
+        })
+    }
+
+    #[test]
+    fn decontaminate_recovers_the_foreground_color_of_a_composite() {
+        let input = composite();
+        let output = decontaminate(input.clone()).unwrap();
+
+        for ((y, x, channel), &value) in output.indexed_iter() {
+            let alpha = input[[y, x, 3]];
+            if channel == 3 || alpha == 0 || alpha == 255 {
+                assert_eq!(value, input[[y, x, channel]]);
+                continue;
+            }
+            // The faintest pixels carry too little foreground to say much.
+            if alpha < 64 {
+                continue;
+            }
+
+            let expected = FOREGROUND[channel] * 255.0;
+            assert!(
+                (value as f32 - expected).abs() < 0.1 * 255.0,
+                "pixel ({}, {}) channel {}: {} instead of {}",
+                y,
+                x,
+                channel,
+                value,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn decontaminate_leaves_opaque_cutouts_alone() {
+        let input = Array3::from_shape_fn((4, 4, 4), |(y, x, channel)| {
+            if channel == 3 {
+                255
+            } else {
+                (y * 40 + x * 10) as u8
+            }
+        });
+
+        assert_eq!(decontaminate(input.clone()).unwrap(), input);
+    }
+
+    #[test]
+    fn decontaminate_rejects_images_without_alpha() {
+        assert!(decontaminate(Array3::zeros((2, 2, 3))).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+use image::{imageops, GrayImage};
+use ndarray::{Array3, Axis, Zip};
+
+use super::base::SessionError;
+
+/// How several masks of the same image are merged into one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FusionMode {
+    #[default]
+    Mean,
+    /// Per-pixel median, which ignores a single pass that gets a region wrong.
+    Median,
+    Max,
+    Min,
+    /// Foreground where most masks say so, averaging the masks that agree.
+    Majority,
+    /// Per-pixel value of the mask that is furthest from 50% alpha.
+    MostConfident,
+}
+
+impl FromStr for FusionMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "mean" => Ok(FusionMode::Mean),
+            "median" => Ok(FusionMode::Median),
+            "max" => Ok(FusionMode::Max),
+            "min" => Ok(FusionMode::Min),
+            "majority" => Ok(FusionMode::Majority),
+            "most_confident" | "most-confident" => Ok(FusionMode::MostConfident),
+            _ => Err(format!(
+                "Unknown fusion mode {}, expected one of: mean, median, max, min, majority, most_confident",
+                value
+            )),
+        }
+    }
+}
+
+/// Merges masks of the same image with `mode`.
+pub(crate) fn fuse(mode: FusionMode, masks: &[&Array3<u8>]) -> Result<Array3<u8>, SessionError> {
+    let first = masks.first().ok_or(SessionError::NoOutput)?;
+    if let Some(mask) = masks.iter().find(|mask| mask.dim() != first.dim()) {
+        log::error!(
+            "Cannot fuse masks of different sizes: {:?} and {:?}",
+            first.dim(),
+            mask.dim()
+        );
+        return Err(SessionError::ImageProcessingError);
+    }
+
+    let views = masks
+        .iter()
+        .map(|mask| mask.view().insert_axis(Axis(0)))
+        .collect::<Vec<_>>();
+    let stacked =
+        ndarray::concatenate(Axis(0), &views).map_err(|_| SessionError::ImageProcessingError)?;
+
+    let fused = stacked.map_axis(Axis(0), |values| match mode {
+        FusionMode::Mean => mean(values.iter().copied()),
+        FusionMode::Max => values.iter().copied().max().unwrap_or(0),
+        FusionMode::Min => values.iter().copied().min().unwrap_or(0),
+        FusionMode::Majority => {
+            let foreground = values.iter().filter(|&&value| value >= 128).count();
+            match (foreground * 2).cmp(&values.len()) {
+                Ordering::Greater => mean(values.iter().copied().filter(|&value| value >= 128)),
+                Ordering::Less => mean(values.iter().copied().filter(|&value| value < 128)),
+                Ordering::Equal => mean(values.iter().copied()),
+            }
+        }
+        FusionMode::MostConfident => values
+            .iter()
+            .copied()
+            .max_by(|&a, &b| confidence(a).total_cmp(&confidence(b)))
+            .unwrap_or(0),
+        FusionMode::Median => {
+            let mut values = values.to_vec();
+            values.sort_unstable();
+            let middle = values.len() / 2;
+            if values.len() % 2 == 0 {
+                (values[middle - 1] as u16 + values[middle] as u16).div_ceil(2) as u8
+            } else {
+                values[middle]
+            }
+        }
+    });
+
+    Ok(fused)
+}
+
+fn mean(values: impl Iterator<Item = u8>) -> u8 {
+    let (sum, count) = values.fold((0_u32, 0_u32), |(sum, count), value| {
+        (sum + value as u32, count + 1)
+    });
+    (sum as f32 / count.max(1) as f32).round() as u8
+}
+
+/// How sure a mask is about a pixel: 0 at 50% alpha, 1 at 0% or 100%.
+fn confidence(alpha: u8) -> f32 {
+    (alpha as f32 / 255.0 - 0.5).abs() * 2.0
+}
+
+/// Averages masks of the same image, weighting each pixel of each mask by
+/// how confident it is. A pass that is unsure about a region, for example
+/// because the subject is too small or too large at its scale, then has little
+/// say there against the passes that are sure. Masks are resized to the size
+/// of the first one.
+pub(crate) fn confidence_weighted_mean<'a>(
+    masks: impl IntoIterator<Item = &'a Array3<u8>>,
+) -> Result<Array3<u8>, SessionError> {
+    let mut masks = masks.into_iter();
+    let first = masks.next().ok_or(SessionError::NoOutput)?;
+
+    // A small floor keeps pixels every mask is unsure about at their mean.
+    let weight = |alpha: u8| confidence(alpha) + 1e-3;
+    let mut sum = first.mapv(|alpha| alpha as f32 * weight(alpha));
+    let mut weights = first.mapv(weight);
+
+    let (height, width, _) = first.dim();
+    for mask in masks {
+        let resized;
+        let mask = if mask.dim() == first.dim() {
+            mask
+        } else {
+            resized = resize_mask(mask.clone(), width as u32, height as u32)?;
+            &resized
+        };
+
+        Zip::from(&mut sum)
+            .and(&mut weights)
+            .and(mask)
+            .for_each(|sum, total, &alpha| {
+                let weight = weight(alpha);
+                *sum += alpha as f32 * weight;
+                *total += weight;
+            });
+    }
+
+    Ok(Zip::from(&sum)
+        .and(&weights)
+        .map_collect(|&sum, &total| (sum / total).round().clamp(0.0, 255.0) as u8))
+}
+
+/// Resizes an `H x W x 1` mask to `width x height` if it is not that size yet.
+pub(crate) fn resize_mask(
+    mask: Array3<u8>,
+    width: u32,
+    height: u32,
+) -> Result<Array3<u8>, SessionError> {
+    let (mask_height, mask_width, _) = mask.dim();
+    if (mask_width as u32, mask_height as u32) == (width, height) {
+        return Ok(mask);
+    }
+
+    let mask = GrayImage::from_raw(mask_width as u32, mask_height as u32, mask.into_raw_vec())
+        .ok_or(SessionError::ImageProcessingError)?;
+    let mask = imageops::resize(&mask, width, height, imageops::Triangle);
+    Array3::from_shape_vec((height as usize, width as usize, 1), mask.into_raw())
+        .map_err(|_| SessionError::ImageProcessingError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask(height: usize, width: usize, values: &[u8]) -> Array3<u8> {
+        Array3::from_shape_vec((height, width, 1), values.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn confidence_weighted_mean_leans_towards_confident_pixels() {
+        let sure = mask(1, 2, &[250, 0]);
+        let unsure = mask(1, 2, &[128, 140]);
+
+        let fused = confidence_weighted_mean([&sure, &unsure]).unwrap();
+
+        // A plain mean would give 189 and 70.
+        assert!(fused[[0, 0, 0]] >= 245, "{}", fused[[0, 0, 0]]);
+        assert!(fused[[0, 1, 0]] <= 15, "{}", fused[[0, 1, 0]]);
+    }
+
+    #[test]
+    fn confidence_weighted_mean_of_a_single_mask_is_that_mask() {
+        let single = mask(2, 3, &[0, 40, 127, 128, 200, 255]);
+
+        assert_eq!(confidence_weighted_mean([&single]).unwrap(), single);
+    }
+
+    #[test]
+    fn confidence_weighted_mean_resizes_masks_to_the_first_one() {
+        let large = mask(4, 4, &[255; 16]);
+        let small = mask(2, 2, &[0; 4]);
+
+        let fused = confidence_weighted_mean([&large, &small]).unwrap();
+
+        assert_eq!(fused.dim(), (4, 4, 1));
+        assert!(fused.iter().all(|&alpha| alpha == 128));
+    }
+
+    #[test]
+    fn fuse_takes_the_mean_max_and_min_per_pixel() {
+        let a = mask(1, 2, &[10, 200]);
+        let b = mask(1, 2, &[30, 100]);
+
+        assert_eq!(
+            fuse(FusionMode::Mean, &[&a, &b]).unwrap(),
+            mask(1, 2, &[20, 150])
+        );
+        assert_eq!(
+            fuse(FusionMode::Max, &[&a, &b]).unwrap(),
+            mask(1, 2, &[30, 200])
+        );
+        assert_eq!(
+            fuse(FusionMode::Min, &[&a, &b]).unwrap(),
+            mask(1, 2, &[10, 100])
+        );
+    }
+
+    #[test]
+    fn fuse_majority_averages_the_masks_that_agree() {
+        let a = mask(1, 2, &[200, 10]);
+        let b = mask(1, 2, &[220, 30]);
+        let c = mask(1, 2, &[10, 250]);
+
+        let fused = fuse(FusionMode::Majority, &[&a, &b, &c]).unwrap();
+
+        assert_eq!(fused, mask(1, 2, &[210, 20]));
+    }
+
+    #[test]
+    fn fuse_majority_tie_falls_back_to_the_mean() {
+        let a = mask(1, 1, &[200]);
+        let b = mask(1, 1, &[50]);
+
+        assert_eq!(
+            fuse(FusionMode::Majority, &[&a, &b]).unwrap(),
+            mask(1, 1, &[125])
+        );
+    }
+
+    #[test]
+    fn fuse_most_confident_picks_the_value_furthest_from_half() {
+        let a = mask(1, 2, &[100, 130]);
+        let b = mask(1, 2, &[240, 20]);
+        let c = mask(1, 2, &[30, 200]);
+
+        let fused = fuse(FusionMode::MostConfident, &[&a, &b, &c]).unwrap();
+
+        assert_eq!(fused, mask(1, 2, &[240, 20]));
+    }
+
+    #[test]
+    fn fuse_rejects_masks_of_different_sizes() {
+        let a = mask(1, 2, &[0, 0]);
+        let b = mask(2, 1, &[0, 0]);
+
+        assert!(fuse(FusionMode::Mean, &[&a, &b]).is_err());
+    }
+}
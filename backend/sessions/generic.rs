@@ -0,0 +1,303 @@
+use image::{DynamicImage, GenericImageView, ImageBuffer};
+use ndarray::{Array3, Array4, Axis};
+use ort::inputs;
+
+use crate::utils::image_helper::{
+    apply_mask_image, rgbau8_to_array3, tensor_f32_to_u8, tensor_resize_bilinear, MaskType,
+};
+
+use super::base::{
+    batch_ranges, split_batch, stack_batch, static_batch_size, BaseSession, BaseSessionTrait,
+    MattingOptions, SessionError, SessionOptions,
+};
+use super::descriptor::{ModelDescriptor, Normalization, ResizeStrategy, TensorLayout};
+use super::letterbox::Letterbox;
+use super::tensor::ElementType;
+use super::upsample::Upsampler;
+
+/// Element types accepted for the image input and the mask output, covering
+/// FP32, FP16 and `uint8` exports.
+const SUPPORTED_TYPES: [ElementType; 3] = [
+    ElementType::Float32,
+    ElementType::Float16,
+    ElementType::Uint8,
+];
+
+/// Matting session driven entirely by a [`ModelDescriptor`].
+pub struct GenericSession {
+    pub descriptor: ModelDescriptor,
+    pub model_name: String,
+    /// The descriptor's `input_name`, or the model's first input.
+    pub(crate) input_name: String,
+    /// Whether the model accepts any input height and width.
+    pub(crate) dynamic_size: bool,
+    pub(crate) input_type: ElementType,
+    pub(crate) output_type: ElementType,
+    pub(crate) base_session: Option<BaseSession>,
+}
+
+impl GenericSession {
+    pub fn new(
+        debug: bool,
+        session_options: SessionOptions,
+        descriptor: ModelDescriptor,
+    ) -> Result<Self, SessionError> {
+        let model_path = descriptor.model_path()?;
+        let base_session = BaseSession::from_path(debug, session_options, &model_path)?;
+
+        let size = Some(descriptor.input_size as usize);
+        let input_dims = match descriptor.layout {
+            TensorLayout::Nchw => [None, Some(3), size, size],
+            TensorLayout::Nhwc => [None, size, size, Some(3)],
+        };
+        let mut input_name = String::new();
+        let mut dynamic_size = false;
+        let mut input_type = ElementType::Float32;
+        let mut output_type = ElementType::Float32;
+        base_session.validate(|metadata| {
+            let input = metadata.input(descriptor.input_name.as_deref())?;
+            input.check_dims("input", &input_dims)?;
+            input_name = input.name.clone();
+            dynamic_size = match descriptor.layout {
+                TensorLayout::Nchw => input.dim(2).is_none() || input.dim(3).is_none(),
+                TensorLayout::Nhwc => input.dim(1).is_none() || input.dim(2).is_none(),
+            };
+            input_type = ElementType::of(input, "input", &SUPPORTED_TYPES)?;
+
+            let output = metadata.output(descriptor.output_index)?;
+            if output.rank() < 2 {
+                return Err(SessionError::ModelLoadError(format!(
+                    "Model output {} is not an image mask",
+                    output
+                )));
+            }
+            output_type = ElementType::of(output, "output", &SUPPORTED_TYPES)?;
+            Ok(())
+        })?;
+
+        Ok(Self {
+            model_name: descriptor.name.clone(),
+            input_name,
+            dynamic_size,
+            input_type,
+            output_type,
+            descriptor,
+            base_session: Some(base_session),
+        })
+    }
+
+    /// Builds the input tensor in the layout and normalization of the descriptor.
+    /// Models with `uint8` inputs get the raw pixels and normalize them themselves.
+    fn pre_process(
+        &self,
+        image: &DynamicImage,
+        options: &MattingOptions,
+    ) -> (Array4<f32>, Letterbox) {
+        let descriptor = &self.descriptor;
+        let letterbox = Letterbox::new(
+            options.resize_mode.unwrap_or(descriptor.resize_mode),
+            image.width(),
+            image.height(),
+            descriptor.input_size,
+            options.scale,
+            self.dynamic_size,
+        );
+        let resized_img = letterbox.apply(image, descriptor.input_filter());
+
+        let (scale, mean, std) = match (self.input_type, descriptor.normalization) {
+            (ElementType::Uint8, _) => (1.0, [0.0; 3], [1.0; 3]),
+            (_, Normalization::Unit) => (1.0 / 255.0, descriptor.mean, descriptor.std),
+            (_, Normalization::Raw) => (1.0, descriptor.mean, descriptor.std),
+        };
+
+        let (width, height) = (letterbox.width as usize, letterbox.height as usize);
+        let shape = match descriptor.layout {
+            TensorLayout::Nchw => (1, 3, height, width),
+            TensorLayout::Nhwc => (1, height, width, 3),
+        };
+
+        let tensor = Array4::from_shape_fn(shape, |(_, a, b, c)| {
+            let (y, x, channel) = match descriptor.layout {
+                TensorLayout::Nchw => (b, c, a),
+                TensorLayout::Nhwc => (a, b, c),
+            };
+            let value = resized_img.get_pixel(x as u32, y as u32)[channel] as f32 * scale;
+            (value - mean[channel]) / std[channel]
+        });
+
+        (tensor, letterbox)
+    }
+}
+
+impl GenericSession {
+    /// Turns the raw mask of one image into a `u8` mask at the size of
+    /// `original_image`, scaled with `upsampler` if given.
+    fn mask_to_original(
+        &self,
+        output: ndarray::ArrayViewD<'_, f32>,
+        letterbox: &Letterbox,
+        original_image: &DynamicImage,
+        upsampler: Option<Upsampler>,
+    ) -> Result<Array3<u8>, Box<dyn std::error::Error>> {
+        let (original_width, original_height) = original_image.dimensions();
+        // A single-channel mask has the same element order in both layouts, only
+        // the position of the spatial dimensions differs.
+        let dims = output.shape();
+        let (mask_height, mask_width) = match (self.descriptor.layout, dims.len()) {
+            (TensorLayout::Nhwc, len) if len >= 3 => (dims[len - 3], dims[len - 2]),
+            (_, len) if len >= 2 => (dims[len - 2], dims[len - 1]),
+            _ => return Err(Box::new(SessionError::ImageProcessingError)),
+        };
+
+        let alpha_mask = output.to_shape((mask_height, mask_width))?.to_owned();
+        let alpha_mask = self
+            .descriptor
+            .activation
+            .apply(letterbox.unpad(alpha_mask));
+        if let Some(upsampler) = upsampler {
+            let alpha_mask = upsampler.upsample(&alpha_mask, original_image);
+            return Ok(tensor_f32_to_u8(alpha_mask.insert_axis(Axis(2))));
+        }
+        let (mask_height, mask_width) = alpha_mask.dim();
+        let alpha_mask = alpha_mask.insert_axis(Axis(2));
+
+        match self.descriptor.resize {
+            ResizeStrategy::Bilinear => {
+                let alpha_mask = tensor_resize_bilinear(
+                    alpha_mask,
+                    original_width as usize,
+                    original_height as usize,
+                    false,
+                );
+
+                Ok(tensor_f32_to_u8(alpha_mask))
+            }
+            resize => {
+                let alpha_mask = tensor_f32_to_u8(alpha_mask);
+                let alpha_image = DynamicImage::ImageLuma8(
+                    ImageBuffer::from_vec(
+                        mask_width as u32,
+                        mask_height as u32,
+                        alpha_mask.into_raw_vec(),
+                    )
+                    .unwrap_or_default(),
+                );
+                let output_image =
+                    alpha_image.resize_exact(original_width, original_height, resize.filter_type());
+
+                Ok(Array3::from_shape_vec(
+                    (original_height as usize, original_width as usize, 1_usize),
+                    output_image.to_luma8().into_raw(),
+                )?)
+            }
+        }
+    }
+}
+
+impl BaseSessionTrait for GenericSession {
+    fn get_model_name(&self) -> String {
+        self.model_name.clone()
+    }
+
+    fn input_size(&self) -> u32 {
+        self.descriptor.input_size
+    }
+
+    fn run(
+        &self,
+        original_image: DynamicImage,
+        options: &MattingOptions,
+    ) -> Result<ndarray::Array3<u8>, Box<dyn std::error::Error>> {
+        log::info!(
+            "Original image size: {}x{}",
+            original_image.width(),
+            original_image.height()
+        );
+
+        self.run_batch(std::slice::from_ref(&original_image), options)?
+            .pop()
+            .ok_or_else(|| Box::new(SessionError::NoOutput).into())
+    }
+
+    fn run_batch(
+        &self,
+        images: &[DynamicImage],
+        options: &MattingOptions,
+    ) -> Result<Vec<ndarray::Array3<u8>>, Box<dyn std::error::Error>> {
+        let model = self.get_session();
+
+        if model.is_none() {
+            return Err(Box::new(SessionError::PredictError));
+        }
+
+        let model = model.unwrap();
+
+        let static_batch = static_batch_size(model);
+        let chunk_size = static_batch.unwrap_or(images.len()).max(1);
+        let mut masks = Vec::with_capacity(images.len());
+
+        let (input_tensors, letterboxes): (Vec<_>, Vec<_>) = images
+            .iter()
+            .map(|image| self.pre_process(image, options))
+            .unzip();
+
+        // Letterboxed images of dynamic-size models differ in size and can
+        // only be batched with images of the same shape.
+        for range in batch_ranges(&input_tensors, chunk_size) {
+            let chunk = &images[range.clone()];
+            log::info!(
+                "Running {} on a batch of {} image(s)",
+                self.model_name,
+                chunk.len()
+            );
+
+            let input_tensor = stack_batch(
+                &input_tensors[range.clone()],
+                static_batch.unwrap_or(range.len()),
+            )?;
+            let batch_size = input_tensor.len_of(Axis(0));
+
+            let ort_inputs = inputs![
+                self.input_name.as_str() => self.input_type.to_value(input_tensor)?
+            ]?;
+
+            let ort_outputs = model.run(ort_inputs)?;
+
+            if ort_outputs.len() <= self.descriptor.output_index {
+                return Err(Box::new(SessionError::NoOutput));
+            }
+
+            let output_tensor = self
+                .output_type
+                .extract(&ort_outputs[self.descriptor.output_index])?;
+
+            for ((image, letterbox), output) in chunk
+                .iter()
+                .zip(&letterboxes[range])
+                .zip(split_batch(output_tensor.view(), batch_size, chunk.len())?)
+            {
+                masks.push(self.mask_to_original(output, letterbox, image, options.upsampler)?);
+            }
+        }
+
+        Ok(masks)
+    }
+
+    fn get_session(&self) -> Option<&ort::Session> {
+        self.base_session
+            .as_ref()
+            .and_then(|s| s.inner_session.as_ref())
+    }
+
+    fn post_process(
+        &self,
+        output: ndarray::Array3<u8>,
+        original_image: DynamicImage,
+    ) -> Result<Array3<u8>, Box<dyn std::error::Error>> {
+        let output_img = rgbau8_to_array3(&original_image.to_rgba8())?;
+
+        let output_img_tensor = apply_mask_image(output_img, output, MaskType::Background);
+
+        Ok(output_img_tensor)
+    }
+}
@@ -1,120 +1,197 @@
-use image::{imageops, DynamicImage};
-use ndarray::Array3;
+use image::{imageops, DynamicImage, GenericImageView, GrayImage, RgbImage};
+use ndarray::{Array3, Array4, Axis};
 use ort::inputs;
 
-use crate::utils::image_helper::{
-    apply_mask_image, rgbau8_to_array3, tensor_f32_to_u8, tensor_hwc_to_bchw,
-    tensor_resize_bilinear, MaskType,
-};
+use super::base::{BaseSession, SessionError, SessionOptions};
+use super::tensor::ElementType;
 
-use super::base::{BaseSession, BaseSessionTrait, SessionError, SessionOptions};
+/// Registry name and file stem of the MI-GAN model.
+pub const INPAINT_MODEL: &str = "migan";
 
+/// MI-GAN inpainting session.
+///
+/// The generator works on a fixed `input_size` square. The region around the
+/// user mask is cropped, resized to that square, filled in, and only the masked
+/// pixels are pasted back into the original image.
 pub struct InpaintSession {
     pub(crate) input_size: u32,
-    pub(crate) mean: [f32; 3],
-    pub(crate) std: [f32; 3],
+    pub(crate) input_type: ElementType,
+    pub(crate) output_type: ElementType,
     pub model_name: String,
     pub(crate) base_session: Option<BaseSession>,
 }
 
 impl InpaintSession {
-    pub fn new(debug: bool, session_options: SessionOptions) -> Self {
-        let model_name = "migan";
-        let base_session = BaseSession::new(debug, session_options, model_name);
-
-        if let Err(error) = base_session {
-            panic!("Failed to create session: {}", error);
-        }
-
-        Self {
-            input_size: 320,
-            mean: [123.675, 116.28, 103.53],
-            std: [58.395, 57.120, 57.375],
+    pub fn new(debug: bool, session_options: SessionOptions) -> Result<Self, SessionError> {
+        let model_name = INPAINT_MODEL;
+        let base_session = BaseSession::new(debug, session_options, model_name)?;
+        let size = Some(512);
+        let supported = [ElementType::Float32, ElementType::Float16];
+        let mut input_type = ElementType::Float32;
+        let mut output_type = ElementType::Float32;
+
+        base_session.validate(|metadata| {
+            let input = metadata.input(None)?;
+            input.check_dims("input", &[None, Some(4), size, size])?;
+            input_type = ElementType::of(input, "input", &supported)?;
+            let output = metadata.output(0)?;
+            output.check_dims("output", &[None, Some(3), size, size])?;
+            output_type = ElementType::of(output, "output", &supported)?;
+            Ok(())
+        })?;
+
+        Ok(Self {
+            input_size: 512,
+            input_type,
+            output_type,
             model_name: model_name.to_string(),
-            base_session: base_session.ok(),
-        }
+            base_session: Some(base_session),
+        })
     }
-}
 
-impl BaseSessionTrait for InpaintSession {
-    fn get_model_name(&self) -> String {
+    pub fn get_model_name(&self) -> String {
         self.model_name.clone()
     }
 
-    fn run(
+    pub fn get_session(&self) -> Option<&ort::Session> {
+        self.base_session
+            .as_ref()
+            .and_then(|s| s.inner_session.as_ref())
+    }
+
+    /// Fills the white area of `mask` in `original_image`.
+    ///
+    /// Returns the inpainted image as an `H x W x 3` RGB array at the original size.
+    pub fn run(
         &self,
         original_image: DynamicImage,
-    ) -> Result<ndarray::Array3<u8>, Box<dyn std::error::Error>> {
-        let mask_size = self.input_size;
-        let original_width = original_image.width();
-        let original_height = original_image.height();
+        mask: DynamicImage,
+    ) -> Result<Array3<u8>, Box<dyn std::error::Error>> {
+        let (original_width, original_height) = original_image.dimensions();
         log::info!(
             "Original image size: {}x{}",
             original_width,
             original_height
         );
 
-        let resized_img = original_image.resize_exact(mask_size, mask_size, imageops::Lanczos3);
-        let image_buffer_array = Array3::<u8>::from_shape_vec(
-            (mask_size as usize, mask_size as usize, 3_usize),
-            resized_img.to_rgb8().into_raw(),
-        );
-
-        if let Err(error) = image_buffer_array {
-            log::error!("Error: {:?}", error);
-            return Err(Box::new(SessionError::ImageProcessingError));
+        let mut output_image = original_image.to_rgb8();
+        let mask = if mask.dimensions() != (original_width, original_height) {
+            mask.resize_exact(original_width, original_height, imageops::Nearest)
+        } else {
+            mask
+        }
+        .to_luma8();
+
+        let crop = match mask_crop_box(&mask, self.input_size) {
+            Some(crop) => crop,
+            None => {
+                log::info!("Empty inpaint mask, returning the original image");
+                return rgb_to_array3(output_image);
+            }
+        };
+        let (crop_x, crop_y, crop_width, crop_height) = crop;
+
+        let image_crop =
+            imageops::crop_imm(&output_image, crop_x, crop_y, crop_width, crop_height).to_image();
+        let mask_crop =
+            imageops::crop_imm(&mask, crop_x, crop_y, crop_width, crop_height).to_image();
+
+        let size = self.input_size;
+        let image_resized = imageops::resize(&image_crop, size, size, imageops::Lanczos3);
+        let mask_resized = imageops::resize(&mask_crop, size, size, imageops::Nearest);
+
+        // MI-GAN expects `[known - 0.5, image * known]` with the image in [-1, 1],
+        // where `known` is 1 outside of the hole and 0 inside.
+        let mut input_tensor = Array4::<f32>::zeros((1, 4, size as usize, size as usize));
+        for (x, y, pixel) in image_resized.enumerate_pixels() {
+            let known = if mask_resized.get_pixel(x, y)[0] > 127 {
+                0.0
+            } else {
+                1.0
+            };
+            let (y, x) = (y as usize, x as usize);
+            input_tensor[[0, 0, y, x]] = known - 0.5;
+            for c in 0..3 {
+                input_tensor[[0, c + 1, y, x]] = (pixel[c] as f32 / 127.5 - 1.0) * known;
+            }
         }
-
-        let input_tensor = tensor_hwc_to_bchw(image_buffer_array.unwrap(), self.mean, self.std);
 
         let model = self.get_session();
 
         if model.is_none() {
-            return Err(Box::new(SessionError::ModelLoadError));
+            return Err(Box::new(SessionError::PredictError));
         }
 
         let model = model.unwrap();
-        let ort_inputs = inputs! {
-            "input.1" => input_tensor.view()
-        }?;
+        let ort_inputs = inputs![self.input_type.to_value(input_tensor)?]?;
 
         let ort_outputs = model.run(ort_inputs)?;
 
-        let output_tensor = ort_outputs[0].try_extract_tensor::<f32>()?;
-
-        let alpha_mask_raw =
-            output_tensor.to_shape((1, 1, mask_size as usize, mask_size as usize))?;
-
-        let alpha_mask = alpha_mask_raw
-            .permuted_axes([2, 3, 1, 0])
-            .to_shape([mask_size as usize, mask_size as usize, 1])?
-            .to_owned();
-
-        let alpha_mask = tensor_resize_bilinear(
-            alpha_mask,
-            original_width as usize,
-            original_height as usize,
-            false,
-        );
+        let output_tensor = self.output_type.extract(&ort_outputs[0])?;
+        let output_tensor = output_tensor
+            .to_shape((1, 3, size as usize, size as usize))?
+            .remove_axis(Axis(0));
+
+        let generated = RgbImage::from_fn(size, size, |x, y| {
+            let (y, x) = (y as usize, x as usize);
+            image::Rgb([0, 1, 2].map(|c| {
+                ((output_tensor[[c, y, x]] + 1.0) * 127.5)
+                    .round()
+                    .clamp(0.0, 255.0) as u8
+            }))
+        });
+        let generated = imageops::resize(&generated, crop_width, crop_height, imageops::Lanczos3);
+
+        for (x, y, pixel) in generated.enumerate_pixels() {
+            if mask_crop.get_pixel(x, y)[0] > 127 {
+                output_image.put_pixel(crop_x + x, crop_y + y, *pixel);
+            }
+        }
 
-        Ok(tensor_f32_to_u8(alpha_mask))
+        rgb_to_array3(output_image)
     }
+}
 
-    fn get_session(&self) -> Option<&ort::Session> {
-        self.base_session
-            .as_ref()
-            .and_then(|s| s.inner_session.as_ref())
+/// Square crop around the mask's bounding box, padded to give the generator
+/// some context and clamped to the image bounds. `None` if the mask is empty.
+fn mask_crop_box(mask: &GrayImage, min_size: u32) -> Option<(u32, u32, u32, u32)> {
+    let (width, height) = mask.dimensions();
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+
+    for (x, y, pixel) in mask.enumerate_pixels() {
+        if pixel[0] > 127 {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
     }
 
-    fn post_process(
-        &self,
-        output: ndarray::Array3<u8>,
-        original_image: DynamicImage,
-    ) -> Result<Array3<u8>, Box<dyn std::error::Error>> {
-        let output_img = rgbau8_to_array3(&original_image.to_rgba8())?;
+    if min_x > max_x {
+        return None;
+    }
 
-        let output_img_tensor = apply_mask_image(output_img, output, MaskType::Background);
+    let box_size = (max_x - min_x + 1).max(max_y - min_y + 1);
+    let side = (box_size * 2).max(min_size);
+    let crop_width = side.min(width);
+    let crop_height = side.min(height);
+
+    let center_x = (min_x + max_x) / 2;
+    let center_y = (min_y + max_y) / 2;
+    let crop_x = center_x
+        .saturating_sub(crop_width / 2)
+        .min(width - crop_width);
+    let crop_y = center_y
+        .saturating_sub(crop_height / 2)
+        .min(height - crop_height);
+
+    Some((crop_x, crop_y, crop_width, crop_height))
+}
 
-        Ok(output_img_tensor)
-    }
+fn rgb_to_array3(image: RgbImage) -> Result<Array3<u8>, Box<dyn std::error::Error>> {
+    let (width, height) = image.dimensions();
+    Ok(Array3::from_shape_vec(
+        (height as usize, width as usize, 3_usize),
+        image.into_raw(),
+    )?)
 }
@@ -0,0 +1,217 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, RgbImage};
+use ndarray::{s, Array2};
+
+use super::descriptor::ResizeMode;
+
+/// Dynamic spatial dimensions are rounded to a multiple of this, which every
+/// common matting backbone downsamples by.
+const DYNAMIC_SIZE_MULTIPLE: u32 = 32;
+
+/// Where an image is placed in the model input: the input size and the area
+/// covered by the resized image. The rest of the input is padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Letterbox {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+    pub content_width: u32,
+    pub content_height: u32,
+}
+
+impl Letterbox {
+    /// Lays out an `image_width x image_height` image for a model with a
+    /// `size x size` input, running at `scale` instead of `size` if given.
+    ///
+    /// `Stretch` resizes the image to a `scale x scale` square, `Letterbox`
+    /// keeps the aspect ratio and resizes it to `scale` on the long side.
+    /// Models with `dynamic` spatial dimensions get exactly that, with each
+    /// side rounded to the nearest multiple of 32. Models with a fixed input
+    /// get the image centered in it and padded; there `scale` can only shrink
+    /// the image.
+    pub fn new(
+        mode: ResizeMode,
+        image_width: u32,
+        image_height: u32,
+        size: u32,
+        scale: Option<u32>,
+        dynamic: bool,
+    ) -> Self {
+        let target = match scale {
+            Some(scale) if dynamic => scale,
+            Some(scale) => scale.min(size),
+            None => size,
+        }
+        .max(1);
+
+        let (width, height) = match mode {
+            ResizeMode::Stretch => (target, target),
+            ResizeMode::Letterbox => {
+                let ratio = target as f32 / image_width.max(image_height).max(1) as f32;
+                (
+                    ((image_width as f32 * ratio).round() as u32).clamp(1, target),
+                    ((image_height as f32 * ratio).round() as u32).clamp(1, target),
+                )
+            }
+        };
+
+        if dynamic {
+            return Self::filled(round_to_multiple(width), round_to_multiple(height));
+        }
+
+        Self {
+            width: size,
+            height: size,
+            x: (size - width) / 2,
+            y: (size - height) / 2,
+            content_width: width,
+            content_height: height,
+        }
+    }
+
+    fn filled(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            x: 0,
+            y: 0,
+            content_width: width,
+            content_height: height,
+        }
+    }
+
+    fn is_padded(&self) -> bool {
+        self.content_width != self.width || self.content_height != self.height
+    }
+
+    /// Resizes `image` into the content area and pads the rest with black.
+    pub fn apply(&self, image: &DynamicImage, filter: FilterType) -> RgbImage {
+        let resized = image
+            .resize_exact(self.content_width, self.content_height, filter)
+            .to_rgb8();
+        if !self.is_padded() {
+            return resized;
+        }
+
+        let mut padded = RgbImage::new(self.width, self.height);
+        imageops::replace(&mut padded, &resized, self.x as i64, self.y as i64);
+        padded
+    }
+
+    /// Crops the padding off a mask predicted for this input. The mask does
+    /// not have to be at the input resolution; the content area is scaled to
+    /// the mask size.
+    pub fn unpad(&self, mask: Array2<f32>) -> Array2<f32> {
+        if !self.is_padded() {
+            return mask;
+        }
+
+        let (mask_height, mask_width) = mask.dim();
+        let crop = |start: u32, length: u32, size: u32, mask_size: usize| {
+            let scale = mask_size as f32 / size as f32;
+            let start = ((start as f32 * scale).round() as usize).min(mask_size - 1);
+            let end = (((start as f32 + length as f32 * scale).round()) as usize)
+                .clamp(start + 1, mask_size);
+            (start, end)
+        };
+        let (x0, x1) = crop(self.x, self.content_width, self.width, mask_width);
+        let (y0, y1) = crop(self.y, self.content_height, self.height, mask_height);
+
+        mask.slice(s![y0..y1, x0..x1]).to_owned()
+    }
+}
+
+fn round_to_multiple(size: u32) -> u32 {
+    let multiples = (size as f32 / DYNAMIC_SIZE_MULTIPLE as f32).round() as u32;
+    multiples.max(1) * DYNAMIC_SIZE_MULTIPLE
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    fn white(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, Rgb([255, 255, 255])))
+    }
+
+    /// The mask a model would predict for `input`: 1 on the image, 0 on the
+    /// black padding.
+    fn predict(input: &RgbImage) -> Array2<f32> {
+        let (width, height) = input.dimensions();
+        Array2::from_shape_fn((height as usize, width as usize), |(y, x)| {
+            input.get_pixel(x as u32, y as u32)[0] as f32 / 255.0
+        })
+    }
+
+    #[test]
+    fn wide_image_is_padded_top_and_bottom_and_unpadded_back() {
+        let letterbox = Letterbox::new(ResizeMode::Letterbox, 640, 320, 320, None, false);
+        assert_eq!(
+            letterbox,
+            Letterbox {
+                width: 320,
+                height: 320,
+                x: 0,
+                y: 80,
+                content_width: 320,
+                content_height: 160,
+            }
+        );
+
+        let input = letterbox.apply(&white(640, 320), FilterType::Triangle);
+        assert_eq!(input.dimensions(), (320, 320));
+        assert_eq!(input.get_pixel(0, 79)[0], 0);
+        assert_eq!(input.get_pixel(0, 80)[0], 255);
+
+        let mask = letterbox.unpad(predict(&input));
+        assert_eq!(mask.dim(), (160, 320));
+        assert!(mask.iter().all(|&alpha| alpha == 1.0));
+    }
+
+    #[test]
+    fn tall_image_is_padded_left_and_right_and_unpadded_at_a_lower_resolution() {
+        let letterbox = Letterbox::new(ResizeMode::Letterbox, 300, 600, 320, None, false);
+        assert_eq!((letterbox.x, letterbox.y), (80, 0));
+        assert_eq!(
+            (letterbox.content_width, letterbox.content_height),
+            (160, 320)
+        );
+
+        let input = letterbox.apply(&white(300, 600), FilterType::Triangle);
+        let half = imageops::resize(&input, 160, 160, FilterType::Nearest);
+
+        let mask = letterbox.unpad(predict(&half));
+        assert_eq!(mask.dim(), (160, 80));
+        assert!(mask.iter().all(|&alpha| alpha == 1.0));
+    }
+
+    #[test]
+    fn fixed_input_scale_can_only_shrink_the_image() {
+        let letterbox = Letterbox::new(ResizeMode::Stretch, 640, 320, 320, Some(1024), false);
+
+        assert_eq!((letterbox.width, letterbox.height), (320, 320));
+        assert_eq!(
+            (letterbox.content_width, letterbox.content_height),
+            (320, 320)
+        );
+    }
+
+    #[test]
+    fn dynamic_dims_are_rounded_to_a_multiple_of_32() {
+        let wide = Letterbox::new(ResizeMode::Letterbox, 1000, 500, 1024, Some(1000), true);
+        assert_eq!((wide.width, wide.height), (992, 512));
+        assert_eq!((wide.content_width, wide.content_height), (992, 512));
+
+        let tall = Letterbox::new(ResizeMode::Letterbox, 500, 1000, 1024, Some(1000), true);
+        assert_eq!((tall.width, tall.height), (512, 992));
+
+        let stretch = Letterbox::new(ResizeMode::Stretch, 1000, 500, 1024, Some(700), true);
+        assert_eq!((stretch.width, stretch.height), (704, 704));
+
+        let mask = Array2::<f32>::ones((512, 992));
+        assert_eq!(wide.unpad(mask.clone()), mask);
+    }
+}
@@ -0,0 +1,236 @@
+use std::str::FromStr;
+
+use ndarray::{Array2, Array3, Axis};
+use ndarray_ndimage::{gaussian_filter, maximum_filter, minimum_filter, BorderMode};
+
+use super::components::ComponentFilter;
+
+/// The Gaussian kernel is cut off at this many standard deviations.
+const GAUSSIAN_TRUNCATE: usize = 4;
+
+/// How the mask is turned into a hard 0/255 mask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Threshold {
+    /// Values above the given one become foreground.
+    Binary(u8),
+    /// The threshold that best separates the two modes of the histogram.
+    Otsu,
+}
+
+impl FromStr for Threshold {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim().to_lowercase();
+        if value == "otsu" {
+            return Ok(Threshold::Otsu);
+        }
+
+        value.parse().map(Threshold::Binary).map_err(|_| {
+            format!(
+                "Invalid threshold {}, expected otsu or a value between 0 and 255",
+                value
+            )
+        })
+    }
+}
+
+/// Grayscale morphology with a square structuring element.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Morphology {
+    /// Shrinks the foreground.
+    Erode,
+    /// Grows the foreground.
+    Dilate,
+    /// Erodes then dilates, removing specks smaller than the element.
+    Open,
+    /// Dilates then erodes, filling gaps smaller than the element.
+    Close,
+}
+
+impl FromStr for Morphology {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "erode" | "erosion" => Ok(Morphology::Erode),
+            "dilate" | "dilation" => Ok(Morphology::Dilate),
+            "open" | "opening" => Ok(Morphology::Open),
+            "close" | "closing" => Ok(Morphology::Close),
+            _ => Err(format!(
+                "Unknown morphology {}, expected one of: erode, dilate, open, close",
+                value
+            )),
+        }
+    }
+}
+
+/// Post-processing of a predicted mask, applied in order: threshold,
+/// morphology, connected-component cleanup, feathering, then gamma and
+/// contrast. Every step is optional; the default does nothing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MaskFilter {
+    pub threshold: Option<Threshold>,
+    /// The operation and the size of its structuring element.
+    pub morphology: Option<(Morphology, u32)>,
+    pub components: ComponentFilter,
+    /// Standard deviation in pixels of the Gaussian blur of the mask.
+    pub feather: Option<f32>,
+    /// Exponent applied to alpha in 0-1: above 1 thins soft edges, below 1
+    /// thickens them.
+    pub gamma: Option<f32>,
+    /// Factor alpha is stretched by around 50%.
+    pub contrast: Option<f32>,
+}
+
+impl MaskFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Runs the configured steps on an `H x W x 1` mask.
+    pub fn apply(&self, mask: Array3<u8>) -> Array3<u8> {
+        if self.is_empty() {
+            return mask;
+        }
+
+        let mut mask = mask.index_axis_move(Axis(2), 0);
+
+        if let Some(threshold) = self.threshold {
+            let threshold = match threshold {
+                Threshold::Binary(threshold) => threshold,
+                Threshold::Otsu => otsu_threshold(&mask),
+            };
+            mask.mapv_inplace(|value| if value > threshold { 255 } else { 0 });
+        }
+
+        if let Some((morphology, size)) = self.morphology {
+            let size = size as usize;
+            let erode = |mask: &Array2<u8>| minimum_filter(mask, size, BorderMode::Nearest, 0);
+            let dilate = |mask: &Array2<u8>| maximum_filter(mask, size, BorderMode::Nearest, 0);
+            mask = match morphology {
+                Morphology::Erode => erode(&mask),
+                Morphology::Dilate => dilate(&mask),
+                Morphology::Open => dilate(&erode(&mask)),
+                Morphology::Close => erode(&dilate(&mask)),
+            };
+        }
+
+        // Components are the regions above the binary threshold if there is
+        // one, otherwise above 50%; an Otsu threshold has already made the
+        // mask binary.
+        let threshold = match self.threshold {
+            Some(Threshold::Binary(threshold)) => threshold,
+            _ => 127,
+        };
+        self.components.apply(&mut mask, threshold);
+
+        if let Some(sigma) = self.feather {
+            mask = feather(mask, sigma);
+        }
+
+        if self.gamma.is_some() || self.contrast.is_some() {
+            let gamma = self.gamma.unwrap_or(1.0);
+            let contrast = self.contrast.unwrap_or(1.0);
+            mask.mapv_inplace(|value| {
+                let alpha = (value as f32 / 255.0).powf(gamma);
+                let alpha = (alpha - 0.5) * contrast + 0.5;
+                (alpha * 255.0).round().clamp(0.0, 255.0) as u8
+            });
+        }
+
+        mask.insert_axis(Axis(2))
+    }
+}
+
+/// Blurs the mask with a Gaussian of standard deviation `sigma`, shrunk if
+/// needed to fit the kernel in the mask.
+fn feather(mask: Array2<u8>, sigma: f32) -> Array2<u8> {
+    let (height, width) = mask.dim();
+    let max_sigma = (height.min(width) as f32 - 1.0) / GAUSSIAN_TRUNCATE as f32;
+    let sigma = sigma.min(max_sigma);
+    if sigma <= 0.0 {
+        return mask;
+    }
+
+    let blurred = gaussian_filter(
+        &mask.mapv(|value| value as f32),
+        sigma,
+        0,
+        BorderMode::Reflect,
+        GAUSSIAN_TRUNCATE,
+    );
+    blurred.mapv(|value| value.round().clamp(0.0, 255.0) as u8)
+}
+
+/// Otsu's threshold: the value that maximizes the variance between the pixels
+/// at or below it and those above it.
+fn otsu_threshold(mask: &Array2<u8>) -> u8 {
+    let mut histogram = [0_u64; 256];
+    for &value in mask {
+        histogram[value as usize] += 1;
+    }
+
+    let total = mask.len() as f64;
+    let sum = histogram
+        .iter()
+        .enumerate()
+        .map(|(value, &count)| value as f64 * count as f64)
+        .sum::<f64>();
+
+    let mut best = (0, 0.0);
+    let mut background_count = 0.0;
+    let mut background_sum = 0.0;
+    for (value, &count) in histogram.iter().enumerate() {
+        background_count += count as f64;
+        background_sum += value as f64 * count as f64;
+        let foreground_count = total - background_count;
+        if background_count == 0.0 || foreground_count == 0.0 {
+            continue;
+        }
+
+        let background_mean = background_sum / background_count;
+        let foreground_mean = (sum - background_sum) / foreground_count;
+        let variance =
+            background_count * foreground_count * (background_mean - foreground_mean).powi(2);
+        if variance > best.1 {
+            best = (value as u8, variance);
+        }
+    }
+
+    best.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn otsu_separates_two_modes() {
+        let mask = Array2::from_shape_fn((4, 4), |(y, _)| if y < 2 { 20 } else { 200 });
+        let threshold = otsu_threshold(&mask);
+
+        assert!((20..200).contains(&threshold));
+    }
+
+    #[test]
+    fn otsu_of_a_uniform_or_empty_mask_is_zero() {
+        assert_eq!(otsu_threshold(&Array2::from_elem((3, 3), 128)), 0);
+        assert_eq!(otsu_threshold(&Array2::zeros((0, 0))), 0);
+    }
+
+    #[test]
+    fn otsu_threshold_makes_the_mask_binary() {
+        let mask = Array2::from_shape_vec((1, 6), vec![0, 30, 60, 190, 220, 250])
+            .unwrap()
+            .insert_axis(Axis(2));
+        let filter = MaskFilter {
+            threshold: Some(Threshold::Otsu),
+            ..Default::default()
+        };
+
+        let mask = filter.apply(mask);
+
+        assert_eq!(mask.into_raw_vec(), vec![0, 0, 0, 255, 255, 255]);
+    }
+}
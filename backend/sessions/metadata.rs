@@ -0,0 +1,162 @@
+use std::fmt;
+
+use ort::{Session, TensorElementType, ValueType};
+
+use super::base::SessionError;
+
+/// Name, element type and shape of one model input or output.
+#[derive(Debug, Clone)]
+pub struct TensorInfo {
+    pub name: String,
+    /// `None` for inputs and outputs that are not tensors.
+    pub element_type: Option<TensorElementType>,
+    /// `None` for dynamic dimensions.
+    pub dims: Vec<Option<usize>>,
+}
+
+impl TensorInfo {
+    fn new(name: &str, value_type: &ValueType) -> Self {
+        Self {
+            name: name.to_owned(),
+            element_type: value_type.tensor_type(),
+            dims: value_type
+                .tensor_dimensions()
+                .map(|dims| {
+                    dims.iter()
+                        .map(|&dim| (dim > 0).then_some(dim as usize))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn rank(&self) -> usize {
+        self.dims.len()
+    }
+
+    /// Size of dimension `index`, or `None` if it is dynamic.
+    pub fn dim(&self, index: usize) -> Option<usize> {
+        self.dims.get(index).copied().flatten()
+    }
+
+    /// Checks the rank and every static dimension against `expected`, where
+    /// `None` accepts any size.
+    pub fn check_dims(&self, kind: &str, expected: &[Option<usize>]) -> Result<(), SessionError> {
+        let matches = self.rank() == expected.len()
+            && self
+                .dims
+                .iter()
+                .zip(expected)
+                .all(|(dim, expected)| match (dim, expected) {
+                    (Some(dim), Some(expected)) => dim == expected,
+                    _ => true,
+                });
+
+        if matches {
+            Ok(())
+        } else {
+            Err(SessionError::ModelLoadError(format!(
+                "Model {} {} does not match the expected shape {}",
+                kind,
+                self,
+                format_dims(expected)
+            )))
+        }
+    }
+}
+
+fn format_dims(dims: &[Option<usize>]) -> String {
+    let dims = dims
+        .iter()
+        .map(|dim| dim.map_or("?".to_owned(), |dim| dim.to_string()))
+        .collect::<Vec<_>>();
+    format!("[{}]", dims.join(", "))
+}
+
+impl fmt::Display for TensorInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.element_type {
+            Some(element_type) => write!(
+                f,
+                "`{}`: {:?} {}",
+                self.name,
+                element_type,
+                format_dims(&self.dims)
+            ),
+            None => write!(f, "`{}`: not a tensor", self.name),
+        }
+    }
+}
+
+/// Inputs and outputs of a loaded model, read from the ONNX session.
+#[derive(Debug, Clone, Default)]
+pub struct ModelMetadata {
+    pub inputs: Vec<TensorInfo>,
+    pub outputs: Vec<TensorInfo>,
+}
+
+impl ModelMetadata {
+    pub fn from_session(session: &Session) -> Self {
+        Self {
+            inputs: session
+                .inputs
+                .iter()
+                .map(|input| TensorInfo::new(&input.name, &input.input_type))
+                .collect(),
+            outputs: session
+                .outputs
+                .iter()
+                .map(|output| TensorInfo::new(&output.name, &output.output_type))
+                .collect(),
+        }
+    }
+
+    /// The input called `name`, or the first input when `name` is `None`.
+    pub fn input(&self, name: Option<&str>) -> Result<&TensorInfo, SessionError> {
+        let input = match name {
+            Some(name) => self.inputs.iter().find(|input| input.name == name),
+            None => self.inputs.first(),
+        };
+
+        input.ok_or_else(|| {
+            SessionError::ModelLoadError(format!(
+                "Model has no input{}, inputs: {}",
+                name.map(|name| format!(" `{}`", name)).unwrap_or_default(),
+                describe(&self.inputs)
+            ))
+        })
+    }
+
+    pub fn output(&self, index: usize) -> Result<&TensorInfo, SessionError> {
+        self.outputs.get(index).ok_or_else(|| {
+            SessionError::ModelLoadError(format!(
+                "Model has no output {}, outputs: {}",
+                index,
+                describe(&self.outputs)
+            ))
+        })
+    }
+}
+
+fn describe(tensors: &[TensorInfo]) -> String {
+    if tensors.is_empty() {
+        return "none".to_owned();
+    }
+
+    tensors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl fmt::Display for ModelMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "inputs: {}; outputs: {}",
+            describe(&self.inputs),
+            describe(&self.outputs)
+        )
+    }
+}
@@ -1,5 +1,22 @@
+pub mod alpha_matting;
 pub mod base;
+pub mod batcher;
 pub mod birefnet;
-// pub mod inpaint;
-// pub mod isnet;
-// pub mod u2net;
+pub mod components;
+pub mod descriptor;
+pub mod ensemble;
+pub mod foreground;
+pub mod fusion;
+pub mod generic;
+pub mod inpaint;
+pub mod letterbox;
+pub mod mask_filter;
+pub mod metadata;
+pub mod pool;
+pub mod realesrgan;
+pub mod registry;
+pub mod settings;
+pub mod tensor;
+pub mod tiling;
+pub mod tta;
+pub mod upsample;
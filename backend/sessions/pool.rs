@@ -0,0 +1,219 @@
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+use rocket::serde::{Deserialize, Serialize};
+
+use super::base::{SessionError, SessionOptions};
+use crate::utils::config::{ConfigCell, ConfigSection};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum PoolMode {
+    /// One session used by all requests at once, with all threads given to it.
+    Shared,
+    /// `size` sessions, each checked out by one request at a time, with the
+    /// threads split evenly between them.
+    Pool,
+}
+
+/// `[default.session_pool]` section of Rocket.toml. The thread budget of each
+/// model comes from its `threads` setting in `[default.sessions]`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+pub struct SessionPoolConfig {
+    pub mode: PoolMode,
+    /// Number of sessions in `pool` mode. Defaults to one, as every session
+    /// holds its own copy of the model in memory.
+    pub size: usize,
+}
+
+impl Default for SessionPoolConfig {
+    fn default() -> Self {
+        Self {
+            mode: PoolMode::Pool,
+            size: 1,
+        }
+    }
+}
+
+impl ConfigSection for SessionPoolConfig {
+    const SECTION: &'static str = "session_pool";
+
+    fn validate(&self) -> Result<(), String> {
+        if self.size == 0 {
+            return Err("size must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// `[default.session_pool]`, validated at ignite.
+pub static SESSION_POOL_CONFIG: ConfigCell<SessionPoolConfig> = ConfigCell::new();
+
+impl SessionPoolConfig {
+    /// Number of sessions to create.
+    pub fn session_count(&self) -> usize {
+        match self.mode {
+            PoolMode::Shared => 1,
+            PoolMode::Pool => self.size.max(1),
+        }
+    }
+
+    /// Splits the intra-op threads of `session_options` (`0` for every core)
+    /// between the sessions. Pooled sessions run their graph with one inter-op
+    /// thread; a shared session keeps its configured inter-op threads.
+    pub fn apply(&self, mut session_options: SessionOptions) -> SessionOptions {
+        let total_threads = match session_options.num_threads() {
+            0 => std::thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(1),
+            threads => threads,
+        };
+
+        match self.mode {
+            PoolMode::Shared => session_options.with_num_threads(total_threads),
+            PoolMode::Pool => session_options
+                .with_num_threads((total_threads / self.session_count()).max(1))
+                .with_inter_threads(1),
+        };
+        session_options
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SessionPoolMetrics {
+    pub mode: PoolMode,
+    pub size: usize,
+    pub in_use: usize,
+    pub checkouts: u64,
+    pub total_wait_ms: f64,
+    pub max_wait_ms: f64,
+}
+
+/// A fixed set of sessions for one model.
+///
+/// [`SessionPool::checkout`] hands out a guard that returns the session to
+/// the pool when dropped. In `shared` mode every checkout gets the same
+/// session without waiting, since `ort::Session::run` only needs `&self`.
+pub struct SessionPool<T> {
+    mode: PoolMode,
+    sessions: Vec<T>,
+    idle: Mutex<Vec<usize>>,
+    available: Condvar,
+    in_use: AtomicUsize,
+    checkouts: AtomicU64,
+    total_wait_nanos: AtomicU64,
+    max_wait_nanos: AtomicU64,
+}
+
+impl<T> SessionPool<T> {
+    /// Creates the sessions with `loader`, stopping at the first failure.
+    pub fn new(
+        config: &SessionPoolConfig,
+        session_options: SessionOptions,
+        loader: impl Fn(SessionOptions) -> Result<T, SessionError>,
+    ) -> Result<Self, SessionError> {
+        let session_options = config.apply(session_options);
+        let sessions = (0..config.session_count())
+            .map(|_| loader(session_options.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            mode: config.mode,
+            idle: Mutex::new((0..sessions.len()).rev().collect()),
+            sessions,
+            available: Condvar::new(),
+            in_use: AtomicUsize::new(0),
+            checkouts: AtomicU64::new(0),
+            total_wait_nanos: AtomicU64::new(0),
+            max_wait_nanos: AtomicU64::new(0),
+        })
+    }
+
+    /// Takes a session out of the pool, blocking until one is free.
+    pub fn checkout(&self) -> SessionGuard<'_, T> {
+        let started = Instant::now();
+
+        let index = match self.mode {
+            PoolMode::Shared => None,
+            PoolMode::Pool => {
+                let mut idle = self.idle.lock().unwrap_or_else(|error| error.into_inner());
+                loop {
+                    if let Some(index) = idle.pop() {
+                        break Some(index);
+                    }
+                    idle = self
+                        .available
+                        .wait(idle)
+                        .unwrap_or_else(|error| error.into_inner());
+                }
+            }
+        };
+
+        let waited = started.elapsed().as_nanos() as u64;
+        self.total_wait_nanos.fetch_add(waited, Ordering::Relaxed);
+        self.max_wait_nanos.fetch_max(waited, Ordering::Relaxed);
+        self.checkouts.fetch_add(1, Ordering::Relaxed);
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+
+        SessionGuard { pool: self, index }
+    }
+
+    fn checkin(&self, index: Option<usize>) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+
+        if let Some(index) = index {
+            self.idle
+                .lock()
+                .unwrap_or_else(|error| error.into_inner())
+                .push(index);
+            self.available.notify_one();
+        }
+    }
+
+    /// Checks out every session of the pool, waiting for each one that is in
+    /// use, e.g. to warm them all up without racing requests for them. In
+    /// `shared` mode that is the one shared session.
+    pub fn checkout_all(&self) -> Vec<SessionGuard<'_, T>> {
+        (0..self.sessions.len()).map(|_| self.checkout()).collect()
+    }
+
+    /// Every session in the pool, regardless of whether it is checked out.
+    pub fn sessions(&self) -> &[T] {
+        &self.sessions
+    }
+
+    pub fn metrics(&self) -> SessionPoolMetrics {
+        SessionPoolMetrics {
+            mode: self.mode,
+            size: self.sessions.len(),
+            in_use: self.in_use.load(Ordering::Relaxed),
+            checkouts: self.checkouts.load(Ordering::Relaxed),
+            total_wait_ms: self.total_wait_nanos.load(Ordering::Relaxed) as f64 / 1e6,
+            max_wait_ms: self.max_wait_nanos.load(Ordering::Relaxed) as f64 / 1e6,
+        }
+    }
+}
+
+/// A checked-out session, returned to its pool on drop.
+pub struct SessionGuard<'a, T> {
+    pool: &'a SessionPool<T>,
+    index: Option<usize>,
+}
+
+impl<T> Deref for SessionGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.pool.sessions[self.index.unwrap_or(0)]
+    }
+}
+
+impl<T> Drop for SessionGuard<'_, T> {
+    fn drop(&mut self) {
+        self.pool.checkin(self.index);
+    }
+}
@@ -0,0 +1,212 @@
+use image::{DynamicImage, GenericImageView, RgbImage};
+use ndarray::{s, Array2, Array3, Array4};
+use ort::inputs;
+
+use super::base::{BaseSession, SessionError, SessionOptions};
+use super::tensor::ElementType;
+use super::tiling::tile_origins;
+
+/// Scales supported by `scripts/RealESRGANExport.py`.
+pub const SUPPORTED_SCALES: [u32; 3] = [2, 4, 8];
+
+/// Name and file stem of the Real-ESRGAN model for `scale`.
+pub fn upscale_model_name(scale: u32) -> String {
+    format!("realesrgan-x{}", scale)
+}
+
+/// Tile size used when the model has dynamic spatial dimensions.
+const DEFAULT_TILE_SIZE: u32 = 256;
+
+/// Real-ESRGAN (RRDBNet) super-resolution session.
+///
+/// Images are processed tile by tile with `tile_overlap` pixels of overlap, and
+/// the overlapping borders are blended with linear ramps to hide the seams.
+/// Only one row of tiles is kept in floating point at a time, so memory use
+/// is bounded by the output image plus `tile_size * scale` output rows; the
+/// `/upscale` route caps the output with `[default.upscale] max_output_pixels`.
+pub struct RealEsrganSession {
+    pub(crate) scale: u32,
+    pub(crate) tile_size: u32,
+    pub(crate) tile_overlap: u32,
+    pub(crate) input_type: ElementType,
+    pub(crate) output_type: ElementType,
+    pub model_name: String,
+    pub(crate) base_session: Option<BaseSession>,
+}
+
+impl RealEsrganSession {
+    pub fn new(
+        debug: bool,
+        session_options: SessionOptions,
+        scale: u32,
+    ) -> Result<Self, SessionError> {
+        let model_name = upscale_model_name(scale);
+        let base_session = BaseSession::new(debug, session_options, &model_name)?;
+
+        // The export script traces the model with a fixed 64x64 input, so the
+        // tile size has to follow the model's static input shape when it has one.
+        let mut tile_size = DEFAULT_TILE_SIZE;
+        let supported = [ElementType::Float32, ElementType::Float16];
+        let mut input_type = ElementType::Float32;
+        let mut output_type = ElementType::Float32;
+        base_session.validate(|metadata| {
+            let input = metadata.input(None)?;
+            input.check_dims("input", &[None, Some(3), None, None])?;
+            input_type = ElementType::of(input, "input", &supported)?;
+            let output = metadata.output(0)?;
+            output.check_dims("output", &[None, Some(3), None, None])?;
+            output_type = ElementType::of(output, "output", &supported)?;
+
+            if let (Some(height), Some(width)) = (input.dim(2), input.dim(3)) {
+                tile_size = height.min(width) as u32;
+            }
+            match (input.dim(2), output.dim(2)) {
+                (Some(input_height), Some(output_height))
+                    if output_height != input_height * scale as usize =>
+                {
+                    Err(SessionError::ModelLoadError(format!(
+                        "Model upscales {}px to {}px, not by x{}",
+                        input_height, output_height, scale
+                    )))
+                }
+                _ => Ok(()),
+            }
+        })?;
+        let base_session = Some(base_session);
+
+        Ok(Self {
+            scale,
+            tile_size,
+            tile_overlap: (tile_size / 8).max(4),
+            input_type,
+            output_type,
+            model_name,
+            base_session,
+        })
+    }
+
+    pub fn get_model_name(&self) -> String {
+        self.model_name.clone()
+    }
+
+    pub fn get_session(&self) -> Option<&ort::Session> {
+        self.base_session
+            .as_ref()
+            .and_then(|s| s.inner_session.as_ref())
+    }
+
+    /// Upscales `original_image` by `self.scale`.
+    ///
+    /// Returns an `(H * scale) x (W * scale) x 3` RGB array.
+    pub fn run(
+        &self,
+        original_image: DynamicImage,
+    ) -> Result<Array3<u8>, Box<dyn std::error::Error>> {
+        let (original_width, original_height) = original_image.dimensions();
+        log::info!(
+            "Original image size: {}x{}, upscaling x{} with {}px tiles",
+            original_width,
+            original_height,
+            self.scale,
+            self.tile_size
+        );
+
+        let model = self.get_session();
+
+        if model.is_none() {
+            return Err(Box::new(SessionError::PredictError));
+        }
+
+        let model = model.unwrap();
+        let image = original_image.to_rgb8();
+        let scale = self.scale as usize;
+        let tile = self.tile_size as usize;
+        let output_width = original_width as usize * scale;
+        let output_height = original_height as usize * scale;
+        let strip_height = tile * scale;
+
+        let mut output = Array3::<u8>::zeros((output_height, output_width, 3));
+        let mut strip = Array3::<f32>::zeros((strip_height, output_width, 3));
+        let mut strip_weight = Array2::<f32>::zeros((strip_height, output_width));
+
+        let rows = tile_origins(original_height, self.tile_size, self.tile_overlap);
+        let columns = tile_origins(original_width, self.tile_size, self.tile_overlap);
+        let ramp = (self.tile_overlap as usize * scale).max(1) as f32;
+
+        for (row_index, &tile_y) in rows.iter().enumerate() {
+            let strip_top = tile_y as usize * scale;
+
+            for &tile_x in &columns {
+                let input_tensor = tile_tensor(&image, tile_x, tile_y, self.tile_size);
+                let ort_inputs = inputs![self.input_type.to_value(input_tensor)?]?;
+                let ort_outputs = model.run(ort_inputs)?;
+                let output_tensor = self.output_type.extract(&ort_outputs[0])?;
+                let output_tensor = output_tensor.to_shape((1, 3, strip_height, strip_height))?;
+
+                // Tiles hanging over the image edge were padded; drop that part.
+                let valid_height = (original_height - tile_y).min(self.tile_size) as usize * scale;
+                let valid_width = (original_width - tile_x).min(self.tile_size) as usize * scale;
+                let left = tile_x as usize * scale;
+
+                for y in 0..valid_height {
+                    for x in 0..valid_width {
+                        let edge_distance =
+                            y.min(x).min(valid_height - 1 - y).min(valid_width - 1 - x);
+                        let weight = ((edge_distance + 1) as f32 / ramp).min(1.0);
+                        for c in 0..3 {
+                            strip[[y, left + x, c]] +=
+                                output_tensor[[0, c, y, x]].clamp(0.0, 1.0) * weight;
+                        }
+                        strip_weight[[y, left + x]] += weight;
+                    }
+                }
+            }
+
+            // Rows above the next tile row will not be touched again.
+            let next_top = rows
+                .get(row_index + 1)
+                .map(|&next_y| next_y as usize * scale)
+                .unwrap_or(output_height);
+            let finished_rows = next_top - strip_top;
+
+            for y in 0..finished_rows {
+                for x in 0..output_width {
+                    let weight = strip_weight[[y, x]].max(f32::EPSILON);
+                    for c in 0..3 {
+                        output[[strip_top + y, x, c]] = (strip[[y, x, c]] / weight * 255.0)
+                            .round()
+                            .clamp(0.0, 255.0)
+                            as u8;
+                    }
+                }
+            }
+
+            let remaining_rows = strip_height - finished_rows;
+            let shifted = strip.slice(s![finished_rows.., .., ..]).to_owned();
+            strip.fill(0.0);
+            strip
+                .slice_mut(s![..remaining_rows, .., ..])
+                .assign(&shifted);
+            let shifted_weight = strip_weight.slice(s![finished_rows.., ..]).to_owned();
+            strip_weight.fill(0.0);
+            strip_weight
+                .slice_mut(s![..remaining_rows, ..])
+                .assign(&shifted_weight);
+        }
+
+        Ok(output)
+    }
+}
+
+/// Builds a `1 x 3 x tile x tile` tensor in [0, 1], repeating edge pixels when
+/// the tile hangs over the image border.
+fn tile_tensor(image: &RgbImage, tile_x: u32, tile_y: u32, tile_size: u32) -> Array4<f32> {
+    let (width, height) = image.dimensions();
+    let tile = tile_size as usize;
+
+    Array4::from_shape_fn((1, 3, tile, tile), |(_, c, y, x)| {
+        let source_x = (tile_x + x as u32).min(width - 1);
+        let source_y = (tile_y + y as u32).min(height - 1);
+        image.get_pixel(source_x, source_y)[c] as f32 / 255.0
+    })
+}
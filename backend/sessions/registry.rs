@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use once_cell::sync::{Lazy, OnceCell};
+
+use super::base::{BaseSessionTrait, SessionError, SessionOptions};
+use super::birefnet::BirefnetSession;
+use super::descriptor::{Activation, ModelDescriptor};
+use super::generic::GenericSession;
+use super::pool::{SessionGuard, SessionPool, SESSION_POOL_CONFIG};
+use super::settings::SESSION_SETTINGS;
+
+pub const DEFAULT_MODEL: &str = "birefnet";
+
+pub type DynSession = dyn BaseSessionTrait + Send + Sync;
+
+type LoadResult = Result<Box<DynSession>, SessionError>;
+
+type SessionLoader = Box<dyn Fn(bool, SessionOptions) -> LoadResult + Send + Sync>;
+
+pub type DynSessionPool = SessionPool<Box<DynSession>>;
+
+/// A registered model: how to build it and the outcome of loading it.
+///
+/// The load result is cached either way, so a model that failed to load keeps
+/// reporting the same error instead of being retried on every request.
+pub struct ModelEntry {
+    pub name: String,
+    loader: SessionLoader,
+    pool: OnceCell<Result<DynSessionPool, SessionError>>,
+}
+
+impl ModelEntry {
+    fn new(
+        name: &str,
+        loader: impl Fn(bool, SessionOptions) -> LoadResult + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.to_owned(),
+            loader: Box::new(loader),
+            pool: OnceCell::new(),
+        }
+    }
+
+    fn from_descriptor(descriptor: ModelDescriptor) -> Self {
+        Self::new(&descriptor.name.clone(), move |debug, options| {
+            let mut descriptor = descriptor.clone();
+            let settings = SESSION_SETTINGS.get().model(&descriptor.name);
+            if let Some(activation) = settings.activation {
+                descriptor.activation = activation;
+            }
+            if let Some(resize_mode) = settings.resize_mode {
+                descriptor.resize_mode = resize_mode;
+            }
+
+            Ok(Box::new(GenericSession::new(debug, options, descriptor)?))
+        })
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.pool().is_some()
+    }
+
+    /// The session pool, if the model has been loaded successfully.
+    pub fn pool(&self) -> Option<&DynSessionPool> {
+        self.pool.get().and_then(|result| result.as_ref().ok())
+    }
+
+    /// The error from the last load attempt, if loading failed.
+    pub fn load_error(&self) -> Option<&SessionError> {
+        self.pool.get().and_then(|result| result.as_ref().err())
+    }
+}
+
+pub struct ModelRegistry {
+    models: HashMap<String, ModelEntry>,
+}
+
+impl ModelRegistry {
+    /// Registers the built-in models, then every descriptor found in the model
+    /// search paths. A descriptor file replaces a built-in model of the same name.
+    fn new() -> Self {
+        let mut entries = vec![ModelEntry::new("birefnet", |debug, options| {
+            let settings = SESSION_SETTINGS.get().model("birefnet");
+            Ok(Box::new(BirefnetSession::new(
+                debug,
+                options,
+                settings.activation.unwrap_or(Activation::None),
+                settings.resize_mode.unwrap_or_default(),
+            )?))
+        })];
+        entries.extend(
+            ModelDescriptor::builtin()
+                .into_iter()
+                .chain(ModelDescriptor::discover())
+                .map(ModelEntry::from_descriptor),
+        );
+
+        let mut models = HashMap::new();
+        for entry in entries {
+            let name = entry.name.to_lowercase();
+            if models.contains_key(&name) {
+                log::info!("Model descriptor overrides model: {}", name);
+            }
+            models.insert(name, entry);
+        }
+
+        Self { models }
+    }
+
+    /// Names of all registered models, sorted, including disabled ones.
+    pub fn all_model_names(&self) -> Vec<String> {
+        let mut names = self.models.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Names of the registered models that are enabled in `[default.sessions]`, sorted.
+    pub fn model_names(&self) -> Vec<String> {
+        self.all_model_names()
+            .into_iter()
+            .filter(|name| SESSION_SETTINGS.get().is_enabled(name))
+            .collect()
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&ModelEntry> {
+        self.models.get(&name.trim().to_lowercase())
+    }
+
+    /// Loaded models, sorted by name.
+    pub fn loaded(&self) -> Vec<&ModelEntry> {
+        let mut entries = self
+            .models
+            .values()
+            .filter(|entry| entry.is_loaded())
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries
+    }
+
+    /// A session of an already loaded model, for work that does not run the
+    /// model such as post-processing. The session is not checked out.
+    pub fn loaded_session(&self, name: &str) -> Option<&DynSession> {
+        self.entry(name)
+            .and_then(ModelEntry::pool)
+            .and_then(|pool| pool.sessions().first())
+            .map(|session| session.as_ref())
+    }
+
+    /// Checks out a session for `name`, loading the model's session pool on
+    /// first use. Blocks while every session of the pool is busy.
+    pub fn get(
+        &self,
+        name: &str,
+        session_options: SessionOptions,
+    ) -> Result<SessionGuard<'_, Box<DynSession>>, SessionError> {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| SessionError::UnknownModel(name.to_owned()))?;
+        if !SESSION_SETTINGS.get().is_enabled(&entry.name) {
+            return Err(SessionError::ModelDisabled(entry.name.clone()));
+        }
+
+        let pool = entry.pool.get_or_init(|| {
+            let config = SESSION_POOL_CONFIG.get();
+            log::info!(
+                "Loading model: {} ({:?} mode, {} sessions)",
+                entry.name,
+                config.mode,
+                config.session_count()
+            );
+            SessionPool::new(config, session_options, |options| {
+                (entry.loader)(true, options)
+            })
+            .inspect_err(|error| {
+                log::error!("Failed to load model {}: {}", entry.name, error);
+            })
+        });
+
+        match pool {
+            Ok(pool) => Ok(pool.checkout()),
+            Err(error) => Err(error.clone()),
+        }
+    }
+}
+
+pub static MODEL_REGISTRY: Lazy<ModelRegistry> = Lazy::new(ModelRegistry::new);
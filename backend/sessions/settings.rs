@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use rocket::figment::Figment;
+use rocket::serde::{Deserialize, Serialize};
+
+use super::base::{OptLevel, SessionError, SessionOptions, PROVIDERS};
+use super::descriptor::{Activation, ResizeMode};
+use crate::utils::config::{ConfigCell, ConfigSection};
+
+/// Session settings of one model. Every field is optional so a model section
+/// only needs to list what differs from the defaults.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+pub struct ModelSettings {
+    pub enabled: Option<bool>,
+    pub providers: Option<Vec<String>>,
+    /// Intra-op threads of the model, split between its pooled sessions.
+    /// `0` uses every available core.
+    pub threads: Option<usize>,
+    pub inter_threads: Option<usize>,
+    pub opt_level: Option<OptLevel>,
+    pub parallel_execution: Option<bool>,
+    pub memory_pattern: Option<bool>,
+    pub cpu_arena: Option<bool>,
+    pub deterministic: Option<bool>,
+    pub strict_providers: Option<bool>,
+    /// Transform of the raw mask output of matting models, replacing the
+    /// model's own. Ignored by MI-GAN and Real-ESRGAN.
+    pub activation: Option<Activation>,
+    /// How matting models fit images into their input when the request does
+    /// not say, replacing the model's own default.
+    pub resize_mode: Option<ResizeMode>,
+}
+
+/// `[default.sessions]` section of Rocket.toml: defaults for every model in
+/// `[default.sessions.defaults]`, and per-model overrides in
+/// `[default.sessions.models.<name>]`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+pub struct SessionSettings {
+    pub defaults: ModelSettings,
+    pub models: HashMap<String, ModelSettings>,
+}
+
+/// The settings a model is actually loaded with.
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct EffectiveSettings {
+    pub enabled: bool,
+    pub providers: Vec<String>,
+    pub threads: usize,
+    pub inter_threads: usize,
+    pub opt_level: OptLevel,
+    pub parallel_execution: bool,
+    pub memory_pattern: bool,
+    pub cpu_arena: bool,
+    pub deterministic: bool,
+    pub strict_providers: bool,
+    /// `None` keeps the model's own transform.
+    pub activation: Option<Activation>,
+    /// `None` keeps the model's own resize mode.
+    pub resize_mode: Option<ResizeMode>,
+}
+
+impl Default for EffectiveSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            providers: vec!["cpu".to_owned()],
+            threads: 0,
+            inter_threads: 1,
+            opt_level: OptLevel::Level3,
+            parallel_execution: false,
+            memory_pattern: true,
+            cpu_arena: true,
+            deterministic: false,
+            strict_providers: false,
+            activation: None,
+            resize_mode: None,
+        }
+    }
+}
+
+impl EffectiveSettings {
+    /// Overrides every field that is set in `settings`.
+    fn merge(mut self, settings: &ModelSettings) -> Self {
+        let settings = settings.clone();
+        self.enabled = settings.enabled.unwrap_or(self.enabled);
+        self.providers = settings.providers.unwrap_or(self.providers);
+        self.threads = settings.threads.unwrap_or(self.threads);
+        self.inter_threads = settings.inter_threads.unwrap_or(self.inter_threads);
+        self.opt_level = settings.opt_level.unwrap_or(self.opt_level);
+        self.parallel_execution = settings
+            .parallel_execution
+            .unwrap_or(self.parallel_execution);
+        self.memory_pattern = settings.memory_pattern.unwrap_or(self.memory_pattern);
+        self.cpu_arena = settings.cpu_arena.unwrap_or(self.cpu_arena);
+        self.deterministic = settings.deterministic.unwrap_or(self.deterministic);
+        self.strict_providers = settings.strict_providers.unwrap_or(self.strict_providers);
+        self.activation = settings.activation.or(self.activation);
+        self.resize_mode = settings.resize_mode.or(self.resize_mode);
+        self
+    }
+
+    pub fn session_options(&self) -> SessionOptions {
+        let mut session_options = SessionOptions::new();
+        session_options
+            .with_providers(self.providers.clone())
+            .with_num_threads(self.threads)
+            .with_inter_threads(self.inter_threads)
+            .with_opt_level(self.opt_level.clone())
+            .with_parallel_execution(self.parallel_execution)
+            .with_memory_pattern(self.memory_pattern)
+            .with_cpu_arena(self.cpu_arena)
+            .with_deterministic(self.deterministic)
+            .with_strict_providers(self.strict_providers);
+        session_options
+    }
+}
+
+impl ConfigSection for SessionSettings {
+    const SECTION: &'static str = "sessions";
+
+    /// Model sections are keyed by their lowercased name.
+    fn from_figment(figment: &Figment) -> Result<Self, String> {
+        if !figment.contains(Self::SECTION) {
+            return Ok(Self::default());
+        }
+
+        let mut settings: Self = figment
+            .extract_inner(Self::SECTION)
+            .map_err(|error| format!("Invalid [sessions] config: {}", error))?;
+        settings.models = settings
+            .models
+            .into_iter()
+            .map(|(name, model_settings)| (name.trim().to_lowercase(), model_settings))
+            .collect();
+
+        Ok(settings)
+    }
+}
+
+impl SessionSettings {
+    /// Effective settings of `model`: the built-in defaults, then the
+    /// `[sessions]` defaults, then the model's own section.
+    pub fn model(&self, model: &str) -> EffectiveSettings {
+        let settings = EffectiveSettings::default().merge(&self.defaults);
+        match self.models.get(&model.trim().to_lowercase()) {
+            Some(model_settings) => settings.merge(model_settings),
+            None => settings,
+        }
+    }
+
+    pub fn is_enabled(&self, model: &str) -> bool {
+        self.model(model).enabled
+    }
+
+    /// Session options for loading `model`, or an error if it is disabled.
+    pub fn session_options(&self, model: &str) -> Result<SessionOptions, SessionError> {
+        let settings = self.model(model);
+        if !settings.enabled {
+            return Err(SessionError::ModelDisabled(model.to_owned()));
+        }
+
+        Ok(settings.session_options())
+    }
+
+    /// Checks that every provider is known and every model section refers to
+    /// one of `known_models`.
+    pub fn validate(&self, known_models: &[String]) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        for (name, settings) in std::iter::once(("defaults", &self.defaults)).chain(
+            self.models
+                .iter()
+                .map(|(name, settings)| (name.as_str(), settings)),
+        ) {
+            for provider in settings.providers.iter().flatten() {
+                if !PROVIDERS.contains(&provider.as_str()) {
+                    errors.push(format!(
+                        "{}: unknown provider {}, expected one of: {}",
+                        name,
+                        provider,
+                        PROVIDERS.join(", ")
+                    ));
+                }
+            }
+        }
+
+        let mut unknown_models = self
+            .models
+            .keys()
+            .filter(|name| !known_models.contains(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        unknown_models.sort();
+        for name in unknown_models {
+            errors.push(format!(
+                "unknown model {}, expected one of: {}",
+                name,
+                known_models.join(", ")
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid [sessions] config: {}", errors.join("; ")))
+        }
+    }
+}
+
+/// `[default.sessions]`, validated at ignite by the settings fairing; the
+/// same settings are logged and served by `/models`.
+pub static SESSION_SETTINGS: ConfigCell<SessionSettings> = ConfigCell::new();
@@ -0,0 +1,110 @@
+use half::f16;
+use ndarray::{Array, ArrayD, Dimension};
+use ort::{DynValue, Tensor, TensorElementType};
+
+use super::base::SessionError;
+use super::metadata::TensorInfo;
+
+/// Element types the sessions can feed to a model and read back.
+///
+/// Preprocessing and postprocessing always work in `f32`; tensors are only
+/// converted right before and after `Session::run`, so FP16 exports and
+/// models with `uint8` inputs or outputs run through the same code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElementType {
+    Float32,
+    Float16,
+    /// Raw `[0, 255]` pixels on input, `[0, 255]` values scaled to `[0, 1]`
+    /// on output.
+    Uint8,
+}
+
+impl ElementType {
+    /// Element type of `info`, which must be one of `supported`.
+    pub fn of(info: &TensorInfo, kind: &str, supported: &[Self]) -> Result<Self, SessionError> {
+        let element_type = match info.element_type {
+            Some(TensorElementType::Float32) => Some(Self::Float32),
+            Some(TensorElementType::Float16) => Some(Self::Float16),
+            Some(TensorElementType::Uint8) => Some(Self::Uint8),
+            _ => None,
+        };
+
+        element_type
+            .filter(|element_type| supported.contains(element_type))
+            .ok_or_else(|| {
+                SessionError::ModelLoadError(format!(
+                    "Model {} {} has an unsupported element type, expected one of: {:?}",
+                    kind, info, supported
+                ))
+            })
+    }
+
+    /// Converts `tensor` into a model input of this element type. `uint8`
+    /// inputs expect `tensor` to hold raw pixel values.
+    pub fn to_value<D: Dimension + 'static>(self, tensor: Array<f32, D>) -> ort::Result<DynValue> {
+        Ok(match self {
+            Self::Float32 => Tensor::from_array(tensor)?.into_dyn(),
+            Self::Float16 => Tensor::from_array(tensor.mapv(f16::from_f32))?.into_dyn(),
+            Self::Uint8 => Tensor::from_array(tensor.mapv(quantize))?.into_dyn(),
+        })
+    }
+
+    /// Reads a model output of this element type as `f32`.
+    pub fn extract(self, value: &DynValue) -> ort::Result<ArrayD<f32>> {
+        Ok(match self {
+            Self::Float32 => value.try_extract_tensor::<f32>()?.to_owned(),
+            Self::Float16 => value.try_extract_tensor::<f16>()?.mapv(f16::to_f32),
+            Self::Uint8 => value.try_extract_tensor::<u8>()?.mapv(dequantize),
+        })
+    }
+}
+
+/// Rounds a raw pixel value for a `uint8` input, clamping it to `[0, 255]`.
+fn quantize(x: f32) -> u8 {
+    x.round().clamp(0.0, 255.0) as u8
+}
+
+/// Scales a `uint8` output value to `[0, 1]`.
+fn dequantize(x: u8) -> f32 {
+    x as f32 / 255.0
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array3;
+
+    use super::*;
+    use crate::utils::image_helper::tensor_f32_to_u8;
+
+    #[test]
+    fn float16_round_trips_within_half_precision() {
+        for x in [0.0, 0.333, 0.5, 1.0, -2.75] {
+            let output = f16::from_f32(x).to_f32();
+            assert!((output - x).abs() < 1e-3, "{} != {}", output, x);
+        }
+    }
+
+    #[test]
+    fn uint8_inputs_are_rounded_and_clamped() {
+        let quantized = [-3.0, 12.4, 12.6, 300.0].map(quantize);
+
+        assert_eq!(quantized, [0, 12, 13, 255]);
+    }
+
+    #[test]
+    fn uint8_round_trips_through_the_unit_range() {
+        for x in 0..=255 {
+            assert_eq!(quantize(dequantize(x) * 255.0), x);
+        }
+    }
+
+    #[test]
+    fn masks_are_clamped_before_quantization() {
+        let mask = Array3::from_shape_vec((1, 4, 1), vec![-0.2, 0.5, 1.0, 1.3]).unwrap();
+
+        assert_eq!(
+            tensor_f32_to_u8(mask).into_raw_vec(),
+            vec![0, 128, 255, 255]
+        );
+    }
+}
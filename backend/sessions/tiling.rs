@@ -0,0 +1,260 @@
+use image::{DynamicImage, GenericImageView};
+use ndarray::{s, Array2, Array3, ArrayView2};
+
+use super::base::{BaseSessionTrait, MattingOptions};
+
+/// Mask values strictly between these are uncertain: neither clearly
+/// background nor clearly foreground.
+const UNCERTAIN_LOW: u8 = 16;
+const UNCERTAIN_HIGH: u8 = 239;
+
+/// Share of uncertain pixels a tile needs to be re-run at full resolution.
+const MIN_UNCERTAIN_FRACTION: f32 = 0.001;
+
+/// Start offsets of overlapping tiles covering `length` pixels. The last tile
+/// is aligned to the end so that every tile lies fully inside the image.
+pub(crate) fn tile_origins(length: u32, tile_size: u32, overlap: u32) -> Vec<u32> {
+    if length <= tile_size {
+        return vec![0];
+    }
+
+    let step = tile_size.saturating_sub(overlap).max(1);
+    let last = length - tile_size;
+    let mut origins = (0..last).step_by(step as usize).collect::<Vec<_>>();
+    origins.push(last);
+    origins
+}
+
+fn is_uncertain(mask: ArrayView2<'_, u8>) -> bool {
+    let uncertain = mask
+        .iter()
+        .filter(|&&value| value > UNCERTAIN_LOW && value < UNCERTAIN_HIGH)
+        .count();
+    uncertain as f32 > mask.len() as f32 * MIN_UNCERTAIN_FRACTION
+}
+
+/// Refines `mask`, the coarse mask `session` predicted for the whole of
+/// `image`, by re-running the model on full-resolution crops.
+///
+/// The image is covered with overlapping tiles of the model's input size.
+/// Tiles whose coarse mask has uncertain pixels are predicted again and
+/// blended in with weights that fade out towards neighbouring tiles, so the
+/// refined areas merge into each other and into the coarse mask without
+/// seams. As in the Real-ESRGAN tiler, only one row of tiles is accumulated at
+/// a time, so memory stays bounded by the mask plus one strip of tiles.
+pub(crate) fn refine_tiled<S: BaseSessionTrait + ?Sized>(
+    session: &S,
+    image: &DynamicImage,
+    mut mask: Array3<u8>,
+    options: &MattingOptions,
+) -> Result<Array3<u8>, Box<dyn std::error::Error>> {
+    let (width, height) = image.dimensions();
+    let tile_size = session.input_size();
+    if width <= tile_size && height <= tile_size {
+        return Ok(mask);
+    }
+
+    let overlap = tile_size / 4;
+    let tile_width = tile_size.min(width);
+    let tile_height = tile_size.min(height);
+    let rows = tile_origins(height, tile_size, overlap);
+    let columns = tile_origins(width, tile_size, overlap);
+    let ramp = overlap.max(1) as f32;
+
+    let (strip_width, strip_height) = (width as usize, tile_height as usize);
+    let (tile_w, tile_h) = (tile_width as usize, tile_height as usize);
+    let mut strip = Array2::<f32>::zeros((strip_height, strip_width));
+    let mut strip_weight = Array2::<f32>::zeros((strip_height, strip_width));
+    let mut refined_tiles = 0;
+
+    for (row_index, &tile_y) in rows.iter().enumerate() {
+        let strip_top = tile_y as usize;
+
+        for &tile_x in &columns {
+            let left = tile_x as usize;
+            let coarse = mask.slice(s![strip_top..strip_top + tile_h, left..left + tile_w, 0]);
+            if !is_uncertain(coarse) {
+                continue;
+            }
+
+            let crop = image.crop_imm(tile_x, tile_y, tile_width, tile_height);
+            let tile_mask = session.run(crop, options)?;
+            refined_tiles += 1;
+
+            // Fade out only towards neighbouring tiles, not the image border.
+            let at_left = tile_x == 0;
+            let at_right = tile_x + tile_width == width;
+            let at_top = tile_y == 0;
+            let at_bottom = tile_y + tile_height == height;
+
+            for y in 0..tile_h {
+                for x in 0..tile_w {
+                    let edge_distance = [
+                        if at_left { usize::MAX } else { x },
+                        if at_right { usize::MAX } else { tile_w - 1 - x },
+                        if at_top { usize::MAX } else { y },
+                        if at_bottom {
+                            usize::MAX
+                        } else {
+                            tile_h - 1 - y
+                        },
+                    ]
+                    .into_iter()
+                    .min()
+                    .unwrap_or(usize::MAX);
+                    let weight = (edge_distance.saturating_add(1) as f32 / ramp).min(1.0);
+
+                    strip[[y, left + x]] += tile_mask[[y, x, 0]] as f32 * weight;
+                    strip_weight[[y, left + x]] += weight;
+                }
+            }
+        }
+
+        // Rows above the next tile row will not be touched again. Where the
+        // tile weights add up to less than one, the coarse mask makes up the rest.
+        let next_top = rows
+            .get(row_index + 1)
+            .map(|&next_y| next_y as usize)
+            .unwrap_or(height as usize);
+        let finished_rows = next_top - strip_top;
+
+        for y in 0..finished_rows {
+            for x in 0..strip_width {
+                let weight = strip_weight[[y, x]];
+                if weight <= 0.0 {
+                    continue;
+                }
+
+                let coarse_weight = (1.0 - weight).max(0.0);
+                let coarse = mask[[strip_top + y, x, 0]] as f32;
+                let value = (strip[[y, x]] + coarse * coarse_weight) / (weight + coarse_weight);
+                mask[[strip_top + y, x, 0]] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+
+        let remaining_rows = strip_height - finished_rows;
+        let shifted = strip.slice(s![finished_rows.., ..]).to_owned();
+        strip.fill(0.0);
+        strip.slice_mut(s![..remaining_rows, ..]).assign(&shifted);
+        let shifted_weight = strip_weight.slice(s![finished_rows.., ..]).to_owned();
+        strip_weight.fill(0.0);
+        strip_weight
+            .slice_mut(s![..remaining_rows, ..])
+            .assign(&shifted_weight);
+    }
+
+    log::info!(
+        "Refined {} of {} tiles at full resolution",
+        refined_tiles,
+        rows.len() * columns.len()
+    );
+
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    /// Predicts a fully opaque mask for every crop, counting the crops.
+    struct StubSession {
+        runs: Cell<usize>,
+    }
+
+    impl BaseSessionTrait for StubSession {
+        fn get_session(&self) -> Option<&ort::Session> {
+            None
+        }
+
+        fn run(
+            &self,
+            original_image: DynamicImage,
+            _options: &MattingOptions,
+        ) -> Result<Array3<u8>, Box<dyn std::error::Error>> {
+            self.runs.set(self.runs.get() + 1);
+            let (width, height) = original_image.dimensions();
+            Ok(Array3::from_elem((height as usize, width as usize, 1), 255))
+        }
+
+        fn post_process(
+            &self,
+            output: Array3<u8>,
+            _original_image: DynamicImage,
+        ) -> Result<Array3<u8>, Box<dyn std::error::Error>> {
+            Ok(output)
+        }
+
+        fn get_model_name(&self) -> String {
+            "stub".to_owned()
+        }
+
+        fn input_size(&self) -> u32 {
+            8
+        }
+    }
+
+    fn refine(mask: Array3<u8>) -> (Array3<u8>, usize) {
+        let session = StubSession { runs: Cell::new(0) };
+        let image = DynamicImage::new_rgb8(16, 16);
+        let mask = refine_tiled(&session, &image, mask, &MattingOptions::default()).unwrap();
+        (mask, session.runs.get())
+    }
+
+    #[test]
+    fn tile_origins_cover_the_length_with_the_last_tile_at_the_end() {
+        assert_eq!(tile_origins(16, 8, 2), vec![0, 6, 8]);
+        assert_eq!(tile_origins(8, 8, 2), vec![0]);
+        assert_eq!(tile_origins(5, 8, 2), vec![0]);
+    }
+
+    #[test]
+    fn only_tiles_with_uncertain_pixels_are_refined() {
+        let mut mask = Array3::zeros((16, 16, 1));
+        mask[[1, 1, 0]] = 128;
+
+        let (refined, runs) = refine(mask);
+
+        // Of the 3 x 3 tiles, only the top-left one covers (1, 1).
+        assert_eq!(runs, 1);
+        assert_eq!(refined[[0, 0, 0]], 255);
+        assert_eq!(refined[[0, 12, 0]], 0);
+        assert_eq!(refined[[12, 0, 0]], 0);
+    }
+
+    #[test]
+    fn refined_tiles_fade_into_the_coarse_mask() {
+        let mut mask = Array3::zeros((16, 16, 1));
+        mask[[1, 1, 0]] = 128;
+
+        let (refined, _) = refine(mask);
+
+        // The top-left tile spans columns 0-7 and fades out over the last
+        // `overlap` (2) columns, towards the tile that starts at column 6.
+        assert_eq!(refined[[0, 6, 0]], 255);
+        assert_eq!(refined[[0, 7, 0]], 128);
+        assert_eq!(refined[[0, 8, 0]], 0);
+    }
+
+    #[test]
+    fn overlapping_refined_tiles_blend_without_seams() {
+        let (refined, runs) = refine(Array3::from_elem((16, 16, 1), 128));
+
+        assert_eq!(runs, 9);
+        assert!(refined.iter().all(|&alpha| alpha == 255));
+    }
+
+    #[test]
+    fn images_that_fit_one_tile_are_left_alone() {
+        let session = StubSession { runs: Cell::new(0) };
+        let image = DynamicImage::new_rgb8(8, 6);
+        let mask = Array3::from_elem((6, 8, 1), 128);
+
+        let refined =
+            refine_tiled(&session, &image, mask.clone(), &MattingOptions::default()).unwrap();
+
+        assert_eq!(refined, mask);
+        assert_eq!(session.runs.get(), 0);
+    }
+}
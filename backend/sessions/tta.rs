@@ -0,0 +1,169 @@
+use std::str::FromStr;
+
+use image::{imageops, DynamicImage, GrayImage};
+use ndarray::Array3;
+
+use super::base::SessionError;
+
+/// Which augmented copies of the image test-time augmentation runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtaMode {
+    /// The image and its horizontal mirror.
+    Flip,
+    /// The mirror plus the image rotated by 90, 180 and 270 degrees.
+    Rotate,
+}
+
+impl FromStr for TtaMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "flip" => Ok(TtaMode::Flip),
+            "rotate" => Ok(TtaMode::Rotate),
+            _ => Err(format!(
+                "Unknown TTA mode {}, expected one of: flip, rotate",
+                value
+            )),
+        }
+    }
+}
+
+/// One augmented copy of the image, and how to map its mask back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Augmentation {
+    Identity,
+    FlipHorizontal,
+    /// Clockwise rotations.
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Augmentation {
+    pub fn for_mode(mode: TtaMode) -> Vec<Self> {
+        match mode {
+            TtaMode::Flip => vec![Self::Identity, Self::FlipHorizontal],
+            TtaMode::Rotate => vec![
+                Self::Identity,
+                Self::FlipHorizontal,
+                Self::Rotate90,
+                Self::Rotate180,
+                Self::Rotate270,
+            ],
+        }
+    }
+
+    pub fn apply(self, image: &DynamicImage) -> DynamicImage {
+        match self {
+            Self::Identity => image.clone(),
+            Self::FlipHorizontal => image.fliph(),
+            Self::Rotate90 => image.rotate90(),
+            Self::Rotate180 => image.rotate180(),
+            Self::Rotate270 => image.rotate270(),
+        }
+    }
+
+    /// Maps the mask of the augmented image back onto the original image.
+    pub fn invert(self, mask: Array3<u8>) -> Result<Array3<u8>, SessionError> {
+        if self == Self::Identity {
+            return Ok(mask);
+        }
+
+        let (height, width, _) = mask.dim();
+        let mask = GrayImage::from_raw(width as u32, height as u32, mask.into_raw_vec())
+            .ok_or(SessionError::ImageProcessingError)?;
+        let mask = match self {
+            Self::Identity => mask,
+            Self::FlipHorizontal => imageops::flip_horizontal(&mask),
+            Self::Rotate90 => imageops::rotate270(&mask),
+            Self::Rotate180 => imageops::rotate180(&mask),
+            Self::Rotate270 => imageops::rotate90(&mask),
+        };
+
+        let (width, height) = mask.dimensions();
+        Array3::from_shape_vec((height as usize, width as usize, 1), mask.into_raw())
+            .map_err(|_| SessionError::ImageProcessingError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fusion::{fuse, FusionMode};
+    use super::*;
+
+    /// A 3 x 2 (wide) mask with a distinct value per pixel.
+    fn mask() -> Array3<u8> {
+        Array3::from_shape_vec((2, 3, 1), vec![0, 10, 20, 30, 40, 50]).unwrap()
+    }
+
+    /// The mask a model that matted `image` perfectly would output.
+    fn predict(image: &DynamicImage) -> Array3<u8> {
+        let image = image.to_luma8();
+        let (width, height) = image.dimensions();
+        Array3::from_shape_vec((height as usize, width as usize, 1), image.into_raw()).unwrap()
+    }
+
+    fn image(mask: &Array3<u8>) -> DynamicImage {
+        let (height, width, _) = mask.dim();
+        DynamicImage::ImageLuma8(
+            GrayImage::from_raw(width as u32, height as u32, mask.iter().copied().collect())
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn invert_undoes_every_augmentation_of_a_non_square_image() {
+        let original = image(&mask());
+
+        for augmentation in Augmentation::for_mode(TtaMode::Rotate) {
+            let augmented = predict(&augmentation.apply(&original));
+            assert_eq!(
+                augmentation.invert(augmented).unwrap(),
+                mask(),
+                "{:?}",
+                augmentation
+            );
+        }
+    }
+
+    #[test]
+    fn rotations_swap_the_sides_of_a_non_square_image() {
+        let original = image(&mask());
+
+        assert_eq!(
+            predict(&Augmentation::Rotate90.apply(&original)).dim(),
+            (3, 2, 1)
+        );
+        assert_eq!(
+            predict(&Augmentation::Rotate180.apply(&original)).dim(),
+            (2, 3, 1)
+        );
+    }
+
+    #[test]
+    fn merged_masks_of_all_augmentations_match_the_original() {
+        let original = image(&mask());
+        let masks = Augmentation::for_mode(TtaMode::Rotate)
+            .into_iter()
+            .map(|augmentation| {
+                augmentation
+                    .invert(predict(&augmentation.apply(&original)))
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let masks = masks.iter().collect::<Vec<_>>();
+
+        assert_eq!(fuse(FusionMode::Median, &masks).unwrap(), mask());
+        assert_eq!(fuse(FusionMode::Mean, &masks).unwrap(), mask());
+    }
+
+    #[test]
+    fn median_merge_ignores_a_single_wrong_pass() {
+        let wrong = Array3::from_elem((2, 3, 1), 255);
+        let masks = [&mask(), &mask(), &wrong];
+
+        assert_eq!(fuse(FusionMode::Median, &masks).unwrap(), mask());
+        assert_ne!(fuse(FusionMode::Mean, &masks).unwrap(), mask());
+    }
+}
@@ -0,0 +1,252 @@
+use std::str::FromStr;
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+use ndarray::Array2;
+
+/// Radius of the guided filter window, in mask pixels.
+const GUIDED_RADIUS: usize = 2;
+/// Regularization of the guided filter. Smaller values follow the image edges
+/// more closely, larger ones stay closer to a plain resize.
+const GUIDED_EPSILON: f32 = 1e-4;
+
+/// Radius of the joint bilateral window, in mask pixels.
+const BILATERAL_RADIUS: isize = 2;
+/// Spatial standard deviation of the joint bilateral weights, in mask pixels.
+const BILATERAL_SPATIAL_SIGMA: f32 = 1.0;
+/// Standard deviation of the joint bilateral weights over luma in 0-1.
+const BILATERAL_RANGE_SIGMA: f32 = 0.1;
+
+/// Edge-aware alternatives to resizing the mask back to the original size
+/// with the model's resize filter. Both use the original image as guidance,
+/// so the edges of the mask snap to the edges of the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Upsampler {
+    /// Fast guided filter (He and Sun): the mask is fitted as a local linear
+    /// function of the image at mask resolution, and that function is
+    /// evaluated on the full-resolution image.
+    Guided,
+    /// Joint bilateral upsampling (Kopf et al.): every pixel averages the
+    /// nearby mask values, weighted by how similar the image is there.
+    JointBilateral,
+}
+
+impl FromStr for Upsampler {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "guided" | "guided_filter" => Ok(Upsampler::Guided),
+            "joint_bilateral" | "bilateral" => Ok(Upsampler::JointBilateral),
+            _ => Err(format!(
+                "Unknown upsampler {}, expected one of: guided, joint_bilateral",
+                value
+            )),
+        }
+    }
+}
+
+impl Upsampler {
+    /// Scales `mask`, alpha in 0-1, to the size of `image`.
+    pub fn upsample(self, mask: &Array2<f32>, image: &DynamicImage) -> Array2<f32> {
+        let (mask_height, mask_width) = mask.dim();
+        let guide = luma(image);
+        let small_guide =
+            luma(&image.resize_exact(mask_width as u32, mask_height as u32, FilterType::Triangle));
+
+        match self {
+            Upsampler::Guided => guided_upsample(mask, &small_guide, &guide),
+            Upsampler::JointBilateral => joint_bilateral_upsample(mask, &small_guide, &guide),
+        }
+    }
+}
+
+fn luma(image: &DynamicImage) -> Array2<f32> {
+    let luma = image.to_luma32f();
+    let (width, height) = luma.dimensions();
+    Array2::from_shape_vec((height as usize, width as usize), luma.into_raw())
+        .unwrap_or_else(|_| Array2::zeros((height as usize, width as usize)))
+}
+
+fn guided_upsample(
+    mask: &Array2<f32>,
+    small_guide: &Array2<f32>,
+    guide: &Array2<f32>,
+) -> Array2<f32> {
+    let mean_guide = box_mean(small_guide, GUIDED_RADIUS);
+    let mean_mask = box_mean(mask, GUIDED_RADIUS);
+    let mean_product = box_mean(&(small_guide * mask), GUIDED_RADIUS);
+    let mean_square = box_mean(&(small_guide * small_guide), GUIDED_RADIUS);
+
+    let covariance = &mean_product - &(&mean_guide * &mean_mask);
+    let variance = &mean_square - &(&mean_guide * &mean_guide);
+    let a = covariance / (variance + GUIDED_EPSILON);
+    let b = &mean_mask - &(&a * &mean_guide);
+
+    let a = box_mean(&a, GUIDED_RADIUS);
+    let b = box_mean(&b, GUIDED_RADIUS);
+
+    let (height, width) = guide.dim();
+    let sampler = Sampler::new(mask.dim(), (height, width));
+    Array2::from_shape_fn((height, width), |(y, x)| {
+        let (a, b) = (sampler.sample(&a, y, x), sampler.sample(&b, y, x));
+        (a * guide[[y, x]] + b).clamp(0.0, 1.0)
+    })
+}
+
+fn joint_bilateral_upsample(
+    mask: &Array2<f32>,
+    small_guide: &Array2<f32>,
+    guide: &Array2<f32>,
+) -> Array2<f32> {
+    let (mask_height, mask_width) = mask.dim();
+    let (height, width) = guide.dim();
+    let sampler = Sampler::new(mask.dim(), (height, width));
+    let spatial = -0.5 / (BILATERAL_SPATIAL_SIGMA * BILATERAL_SPATIAL_SIGMA);
+    let range = -0.5 / (BILATERAL_RANGE_SIGMA * BILATERAL_RANGE_SIGMA);
+
+    Array2::from_shape_fn((height, width), |(y, x)| {
+        let (source_y, source_x) = sampler.position(y, x);
+        let (center_y, center_x) = (source_y.round() as isize, source_x.round() as isize);
+        let luma = guide[[y, x]];
+
+        let mut sum = 0.0;
+        let mut total = 0.0;
+        for dy in -BILATERAL_RADIUS..=BILATERAL_RADIUS {
+            let my = center_y + dy;
+            if my < 0 || my >= mask_height as isize {
+                continue;
+            }
+            for dx in -BILATERAL_RADIUS..=BILATERAL_RADIUS {
+                let mx = center_x + dx;
+                if mx < 0 || mx >= mask_width as isize {
+                    continue;
+                }
+
+                let (my, mx) = (my as usize, mx as usize);
+                let distance = (my as f32 - source_y).powi(2) + (mx as f32 - source_x).powi(2);
+                let difference = small_guide[[my, mx]] - luma;
+                let weight = (distance * spatial + difference * difference * range).exp();
+                sum += weight * mask[[my, mx]];
+                total += weight;
+            }
+        }
+
+        if total > 0.0 {
+            (sum / total).clamp(0.0, 1.0)
+        } else {
+            sampler.sample(mask, y, x)
+        }
+    })
+}
+
+/// Mean over the `(2 * radius + 1)` square around each pixel, shrunk at the
+/// border, from a summed-area table.
+fn box_mean(values: &Array2<f32>, radius: usize) -> Array2<f32> {
+    let (height, width) = values.dim();
+    let mut table = Array2::<f64>::zeros((height + 1, width + 1));
+    for y in 0..height {
+        let mut row = 0.0;
+        for x in 0..width {
+            row += values[[y, x]] as f64;
+            table[[y + 1, x + 1]] = table[[y, x + 1]] + row;
+        }
+    }
+
+    Array2::from_shape_fn((height, width), |(y, x)| {
+        let (y0, y1) = (y.saturating_sub(radius), (y + radius + 1).min(height));
+        let (x0, x1) = (x.saturating_sub(radius), (x + radius + 1).min(width));
+        let sum = table[[y1, x1]] - table[[y0, x1]] - table[[y1, x0]] + table[[y0, x0]];
+        (sum / ((y1 - y0) * (x1 - x0)) as f64) as f32
+    })
+}
+
+/// Maps full-resolution pixels to positions in the mask, aligning pixel
+/// centers, and samples the mask there bilinearly.
+struct Sampler {
+    source: (usize, usize),
+    scale_y: f32,
+    scale_x: f32,
+}
+
+impl Sampler {
+    fn new(source: (usize, usize), target: (usize, usize)) -> Self {
+        Self {
+            source,
+            scale_y: source.0 as f32 / target.0.max(1) as f32,
+            scale_x: source.1 as f32 / target.1.max(1) as f32,
+        }
+    }
+
+    fn position(&self, y: usize, x: usize) -> (f32, f32) {
+        let clamp = |value: f32, size: usize| value.clamp(0.0, size.saturating_sub(1) as f32);
+        (
+            clamp((y as f32 + 0.5) * self.scale_y - 0.5, self.source.0),
+            clamp((x as f32 + 0.5) * self.scale_x - 0.5, self.source.1),
+        )
+    }
+
+    fn sample(&self, values: &Array2<f32>, y: usize, x: usize) -> f32 {
+        let (source_y, source_x) = self.position(y, x);
+        let (y0, x0) = (source_y.floor() as usize, source_x.floor() as usize);
+        let (y1, x1) = (
+            (y0 + 1).min(self.source.0 - 1),
+            (x0 + 1).min(self.source.1 - 1),
+        );
+        let (dy, dx) = (source_y - y0 as f32, source_x - x0 as f32);
+
+        let top = values[[y0, x0]] * (1.0 - dx) + values[[y0, x1]] * dx;
+        let bottom = values[[y1, x0]] * (1.0 - dx) + values[[y1, x1]] * dx;
+        top * (1.0 - dy) + bottom * dy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+
+    use super::*;
+
+    /// A 40 x 30 image with a bright left half and a dark, noisy right half,
+    /// so the guidance is anything but flat.
+    fn image() -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(40, 30, |x, y| {
+            if x < 20 {
+                Luma([220])
+            } else {
+                Luma([((x * 7 + y * 13) % 60) as u8])
+            }
+        }))
+    }
+
+    #[test]
+    fn upsample_scales_the_mask_to_the_image_size() {
+        let mask = Array2::from_shape_fn((8, 10), |(y, x)| ((x + y) % 2) as f32);
+
+        for upsampler in [Upsampler::Guided, Upsampler::JointBilateral] {
+            let upsampled = upsampler.upsample(&mask, &image());
+
+            assert_eq!(upsampled.dim(), (30, 40), "{:?}", upsampler);
+            assert!(
+                upsampled.iter().all(|alpha| (0.0..=1.0).contains(alpha)),
+                "{:?}",
+                upsampler
+            );
+        }
+    }
+
+    #[test]
+    fn upsample_keeps_a_constant_mask_constant() {
+        let mask = Array2::from_elem((8, 10), 0.7);
+
+        for upsampler in [Upsampler::Guided, Upsampler::JointBilateral] {
+            let upsampled = upsampler.upsample(&mask, &image());
+
+            assert!(
+                upsampled.iter().all(|alpha| (alpha - 0.7).abs() < 1e-3),
+                "{:?}",
+                upsampler
+            );
+        }
+    }
+}
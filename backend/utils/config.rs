@@ -0,0 +1,92 @@
+use std::fmt::Debug;
+
+use once_cell::sync::OnceCell;
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::serde::de::DeserializeOwned;
+
+/// A section of Rocket.toml, such as `[default.inference]`.
+///
+/// A missing section gives the defaults. A section that is present but does
+/// not parse, has unknown keys or fails [`ConfigSection::validate`] is an
+/// error, so that a typo aborts launch instead of silently falling back to
+/// the defaults.
+pub trait ConfigSection: DeserializeOwned + Default + Debug + Send + Sync + 'static {
+    /// Key of the section, e.g. `inference`.
+    const SECTION: &'static str;
+
+    /// Checks the values that parse but make no sense.
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn from_figment(figment: &Figment) -> Result<Self, String> {
+        if !figment.contains(Self::SECTION) {
+            return Ok(Self::default());
+        }
+
+        let config: Self = figment
+            .extract_inner(Self::SECTION)
+            .map_err(|error| format!("Invalid [{}] config: {}", Self::SECTION, error))?;
+        config
+            .validate()
+            .map_err(|error| format!("Invalid [{}] config: {}", Self::SECTION, error))?;
+        Ok(config)
+    }
+}
+
+/// Holds a config section validated at ignite by [`ConfigCell::fairing`], for
+/// code that runs outside of request handlers and has no access to managed
+/// state.
+pub struct ConfigCell<T> {
+    cell: OnceCell<T>,
+}
+
+impl<T: ConfigSection + Clone> ConfigCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            cell: OnceCell::new(),
+        }
+    }
+
+    /// The validated section, or the defaults if the fairing has not run, as
+    /// in unit tests.
+    pub fn get(&self) -> &T {
+        self.cell.get_or_init(T::default)
+    }
+
+    /// Stores the validated section. Fails if it has already been stored, or
+    /// read before it was.
+    pub fn set(&self, config: T) -> Result<(), String> {
+        self.cell
+            .set(config)
+            .map_err(|_| format!("[{}] config was read before it was validated", T::SECTION))
+    }
+
+    /// Reads and validates the section on ignite, aborting launch when it is
+    /// invalid, and also puts it in Rocket's managed state.
+    pub fn fairing(&'static self) -> AdHoc {
+        AdHoc::try_on_ignite(T::SECTION, move |rocket| async move {
+            let config = match T::from_figment(rocket.figment()) {
+                Ok(config) => config,
+                Err(error) => {
+                    log::error!("{}", error);
+                    return Err(rocket);
+                }
+            };
+
+            log::info!("[{}] config: {:?}", T::SECTION, config);
+            if let Err(error) = self.set(config.clone()) {
+                log::error!("{}", error);
+                return Err(rocket);
+            }
+            Ok(rocket.manage(config))
+        })
+    }
+}
+
+impl<T: ConfigSection + Clone> Default for ConfigCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -9,9 +9,9 @@ pub fn tensor_resize_bilinear(
     proportional: bool,
 ) -> Array3<f32> {
     let shape = image_tensor.shape();
-    let src_height = shape[0] as usize;
-    let src_width = shape[1] as usize;
-    let src_channels = shape[2] as usize;
+    let src_height = shape[0];
+    let src_width = shape[1];
+    let src_channels = shape[2];
 
     let mut scale_x = src_width as f64 / new_width as f64;
     let mut scale_y = src_height as f64 / new_height as f64;
@@ -39,25 +39,25 @@ pub fn tensor_resize_bilinear(
             let y1 = f64::max(src_y.floor(), 0.0);
             let y2 = f64::min(src_y.ceil(), src_height as f64 - 1.0);
 
-            let dx = src_x - x1 as f64;
-            let dy = src_y - y1 as f64;
+            let dx = src_x - x1;
+            let dy = src_y - y1;
 
             for c in 0..src_channels {
                 let p1 = image_tensor
                     .get((y1 as usize, x1 as usize, c))
-                    .map(|n| *n)
+                    .copied()
                     .unwrap_or_default();
                 let p2 = image_tensor
                     .get((y1 as usize, x2 as usize, c))
-                    .map(|n| *n)
+                    .copied()
                     .unwrap_or_default();
                 let p3 = image_tensor
                     .get((y2 as usize, x1 as usize, c))
-                    .map(|n| *n)
+                    .copied()
                     .unwrap_or_default();
                 let p4 = image_tensor
                     .get((y2 as usize, x2 as usize, c))
-                    .map(|n| *n)
+                    .copied()
                     .unwrap_or_default();
 
                 // Perform bilinear interpolation
@@ -66,9 +66,9 @@ pub fn tensor_resize_bilinear(
                     + (1.0 - dx) * dy * p3 as f64
                     + dx * dy * p4 as f64;
 
-                resized_image_data
-                    .get_mut((y, x, c))
-                    .map(|n| *n = interpolated_value as f32);
+                if let Some(n) = resized_image_data.get_mut((y, x, c)) {
+                    *n = interpolated_value as f32;
+                }
             }
         }
     }
@@ -80,8 +80,10 @@ pub fn tensor_u8_to_f32(input_tensor: Array3<u8>) -> Array3<f32> {
     input_tensor.map(|&n| n as f32 / 255.0)
 }
 
+/// Quantizes values in [0, 1] to [0, 255]. Values outside that range are
+/// clamped.
 pub fn tensor_f32_to_u8(input_tensor: Array3<f32>) -> Array3<u8> {
-    input_tensor.map(|&n| (n * 255.0) as u8)
+    input_tensor.map(|&n| (n * 255.0).round().clamp(0.0, 255.0) as u8)
 }
 
 pub fn tensor_hwc_to_bchw(image_tensor: Array3<u8>, mean: [f32; 3], std: [f32; 3]) -> Array4<f32> {
@@ -126,7 +128,7 @@ pub fn apply_mask_image(
             continue;
         }
 
-        let mask_value = mask_tensor.get((y, x, 0)).map(|n| *n).unwrap_or(0);
+        let mask_value = mask_tensor.get((y, x, 0)).copied().unwrap_or(0);
 
         *value = match mask_type {
             MaskType::Object => {
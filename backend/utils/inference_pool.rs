@@ -0,0 +1,164 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+use rocket::serde::Deserialize;
+use rocket::tokio::sync::Semaphore;
+use rocket::tokio::task;
+use rocket::tokio::time::timeout;
+
+use super::config::ConfigSection;
+
+/// `[default.inference]` section of Rocket.toml.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+pub struct InferencePoolConfig {
+    /// Jobs running at the same time on the blocking pool.
+    pub max_concurrency: usize,
+    /// Jobs allowed to wait for a free slot; further requests get a 429.
+    pub max_queue: usize,
+    /// How long a job may wait for a free slot before it gets a 503.
+    pub queue_timeout_ms: u64,
+}
+
+impl Default for InferencePoolConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrency: 2,
+            max_queue: 32,
+            queue_timeout_ms: 30_000,
+        }
+    }
+}
+
+impl ConfigSection for InferencePoolConfig {
+    const SECTION: &'static str = "inference";
+
+    fn validate(&self) -> Result<(), String> {
+        if self.max_concurrency == 0 {
+            return Err("max_concurrency must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum InferencePoolError {
+    #[error("Inference queue is full")]
+    QueueFull,
+    #[error("Timed out waiting for an inference slot")]
+    QueueTimeout,
+    #[error("Inference job failed: {0}")]
+    JobFailed(String),
+}
+
+/// Counts a job as waiting for a slot until dropped, including when the
+/// request future is dropped mid-wait because the client disconnected.
+struct WaitingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Runs CPU-heavy jobs on tokio's blocking thread pool so they never block the
+/// async workers that serve other requests.
+///
+/// At most `max_concurrency` jobs run at once and at most `max_queue` wait for
+/// a slot; anything beyond that is rejected right away.
+pub struct InferencePool {
+    permits: Arc<Semaphore>,
+    waiting: AtomicUsize,
+    config: InferencePoolConfig,
+}
+
+impl InferencePool {
+    pub fn new(config: InferencePoolConfig) -> Self {
+        let config = InferencePoolConfig {
+            max_concurrency: config.max_concurrency.max(1),
+            ..config
+        };
+        log::info!(
+            "Inference pool: {} concurrent jobs, {} queued, {}ms queue timeout",
+            config.max_concurrency,
+            config.max_queue,
+            config.queue_timeout_ms
+        );
+
+        Self {
+            permits: Arc::new(Semaphore::new(config.max_concurrency)),
+            waiting: AtomicUsize::new(0),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &InferencePoolConfig {
+        &self.config
+    }
+
+    /// Jobs currently waiting for a slot.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// Jobs currently running.
+    pub fn running(&self) -> usize {
+        self.config.max_concurrency - self.permits.available_permits()
+    }
+
+    pub async fn run<T, F>(&self, job: F) -> Result<T, InferencePoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let queued = self.waiting.fetch_add(1, Ordering::SeqCst);
+                let waiting = WaitingGuard(&self.waiting);
+                if queued >= self.config.max_queue {
+                    return Err(InferencePoolError::QueueFull);
+                }
+
+                let permit = timeout(
+                    Duration::from_millis(self.config.queue_timeout_ms),
+                    self.permits.clone().acquire_owned(),
+                )
+                .await;
+                drop(waiting);
+
+                match permit {
+                    Ok(Ok(permit)) => permit,
+                    Ok(Err(error)) => return Err(InferencePoolError::JobFailed(error.to_string())),
+                    Err(_) => return Err(InferencePoolError::QueueTimeout),
+                }
+            }
+        };
+
+        task::spawn_blocking(move || {
+            let _permit = permit;
+            job()
+        })
+        .await
+        .map_err(|error| {
+            log::error!("Inference job failed: {:?}", error);
+            InferencePoolError::JobFailed(error.to_string())
+        })
+    }
+}
+
+/// Reads `[default.inference]` on ignition, aborting launch when it is
+/// invalid, and manages the [`InferencePool`] built from it.
+pub fn inference_pool_fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Inference pool", |rocket| async {
+        match InferencePoolConfig::from_figment(rocket.figment()) {
+            Ok(config) => Ok(rocket.manage(InferencePool::new(config))),
+            Err(error) => {
+                log::error!("{}", error);
+                Err(rocket)
+            }
+        }
+    })
+}
@@ -1 +1,3 @@
+pub mod config;
 pub mod image_helper;
+pub mod inference_pool;